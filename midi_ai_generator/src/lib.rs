@@ -0,0 +1,108 @@
+use std::{fs::File, io::{self, Write}, path::PathBuf};
+use clap::Args;
+use midi_ai_trainer::{load_net, tokenizer::{BOS, EOS}, Network, Vector, CHECKPOINT_FILE};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
+use smai_config::{ConfigArgs, Section};
+
+const LENGTH: usize = 1000;
+
+/// The [generator] section, how many tokens a generated song runs to
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeneratorConfig{
+    pub length: usize
+}
+impl Default for GeneratorConfig{
+    fn default()->Self{
+        Self{length: LENGTH}
+    }
+}
+impl Section for GeneratorConfig{
+    const NAME: &'static str = "generator";
+
+    fn validate(&self)->Result<(), String>{
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+pub struct GenerateArgs{
+    /// File to write the generated text to, `-` for stdout
+    #[arg(default_value = "-")]
+    pub output: PathBuf,
+    /// Trained network to sample from
+    #[arg(long, default_value = CHECKPOINT_FILE)]
+    pub checkpoint: PathBuf,
+    /// Number of tokens to generate, characters unless the network was trained on another tokenizer,
+    /// replacing generator.length from the configuration
+    #[arg(long)]
+    pub length: Option<usize>,
+    /// Seed for reproducible sampling
+    #[arg(long)]
+    pub seed: Option<u64>,
+    #[command(flatten)]
+    pub config: ConfigArgs
+}
+
+pub fn run(args: &GenerateArgs)->io::Result<()>{
+    let config: GeneratorConfig = args.config.load()?;
+    let Some(net) = load_net(&args.checkpoint) else {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("No network at {}", args.checkpoint.display())));
+    };
+    let mut rng = match args.seed{
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_rng(&mut rand::rng())
+    };
+    let song = generate(&net, args.length.unwrap_or(config.length), &mut rng)?;
+
+    if args.output.as_os_str() == "-"{
+        io::stdout().lock().write_all(song.as_bytes())
+    }else{
+        File::create(&args.output)?.write_all(song.as_bytes())
+    }
+}
+
+/// Feeds the network its own samples from BOS on, the same way the trainer feeds it a song, until it samples EOS.
+/// Text from tokenizers other than the character one starts with the header the decompressor needs.
+pub fn generate(net: &Network, length: usize, rng: &mut impl Rng)->io::Result<String>{
+    let tokenizer = net.build_tokenizer()?;
+    let vocabulary_size = tokenizer.vocabulary_size();
+    let mut tokens = Vec::with_capacity(length);
+    let mut input = BOS;
+    let mut hidden_state = Vector::zeros(vocabulary_size);
+
+    while tokens.len() < length{
+        let (output, new_hidden) = net.forward(input, &hidden_state);
+        hidden_state = new_hidden;
+
+        let Some(token) = sample(&output, vocabulary_size, rng) else {break};
+        if token == EOS{
+            break;
+        }
+        // Other special tokens are fed back but write no text
+        tokens.push(token);
+        input = token;
+    }
+    Ok(tokenizer.header().unwrap_or_default() + &tokenizer.decode(&tokens))
+}
+
+/// Picks an index with probability proportional to its (non negative) output, the strongest one if all are zero
+fn sample(output: &Vector, vocabulary_size: usize, rng: &mut impl Rng)->Option<usize>{
+    let weights: Vec<f32> = (0..vocabulary_size)
+        .map_while(|index|output.get(index).map(|weight|weight.max(0.0)))
+        .collect();
+    let total: f32 = weights.iter().sum();
+    if total <= 0.0{
+        return (0..weights.len()).max_by(|a, b|output.get(*a).partial_cmp(&output.get(*b)).unwrap_or(std::cmp::Ordering::Equal));
+    }
+
+    let mut target = rng.random_range(0.0..total);
+    for (index, weight) in weights.iter().enumerate(){
+        if target < *weight{
+            return Some(index);
+        }
+        target -= weight;
+    }
+    weights.len().checked_sub(1)
+}
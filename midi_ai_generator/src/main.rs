@@ -1,3 +1,13 @@
-fn main(){
-    
-}
\ No newline at end of file
+use clap::Parser;
+use midi_ai_generator::GenerateArgs;
+
+#[derive(Parser)]
+#[command(about = "Samples .cary text from a trained network")]
+struct Cli{
+    #[command(flatten)]
+    args: GenerateArgs
+}
+
+fn main()->std::io::Result<()>{
+    midi_ai_generator::run(&Cli::parse().args)
+}
@@ -0,0 +1,170 @@
+/*
+    Byte pair encoding for .cary text
+
+    Starts from one token per .cary character and learns merges from a corpus, most frequent
+    adjacent pair first, so common chords like `DKP ` end up as a single token. Merges never
+    cross a frame's closing space, the text between spaces is tokenized on its own.
+
+    The vocabulary file lists the merges in the order they were learned, one per line as the two
+    tokens merged, with `\s`, `\n`, `\t` and `\\` standing for space, bar marker, beat marker and backslash.
+*/
+
+use std::{collections::HashMap, fs, io, path::Path};
+use smai_config::format::{BAR_MARKER, BEAT_MARKER};
+use crate::{strip_cary_header, tokenizer::{cary_alphabet, Vocabulary, UNK}, Tokenizer};
+
+pub struct BpeTokenizer{
+    vocabulary: Vocabulary, // The base characters, then a token per merge
+    base: HashMap<char, usize>,
+    merges: HashMap<(usize, usize), usize> // Pair of tokens and the token they merge into, earlier merges have lower ids
+}
+impl BpeTokenizer{
+    fn new()->Self{
+        let vocabulary = Vocabulary::new(cary_alphabet().map(String::from));
+        let base = cary_alphabet().filter_map(|char|Some((char, vocabulary.index(&char.to_string())?))).collect();
+        Self{vocabulary, base, merges: HashMap::new()}
+    }
+
+    /// Learns merges from the frames of `songs` until there are `vocabulary_size` tokens
+    /// or no pair appears more than once
+    pub fn learn(songs: &[String], vocabulary_size: usize)->Self{
+        let mut tokenizer = Self::new();
+        let mut frame_counts: HashMap<&str, usize> = HashMap::new();
+        for song in songs{
+            for frame in strip_cary_header(song).split_inclusive(' '){
+                *frame_counts.entry(frame).or_default() += 1;
+            }
+        }
+        let mut frames: Vec<(Vec<usize>, usize)> = frame_counts.into_iter()
+            .map(|(frame, count)|(tokenizer.base_tokens(frame), count))
+            .collect();
+
+        while tokenizer.vocabulary.len() < vocabulary_size{
+            let mut pair_counts: HashMap<(usize, usize), usize> = HashMap::new();
+            for (symbols, count) in &frames{
+                // Unknown characters are never merged
+                for pair in symbols.windows(2).filter(|pair|!pair.contains(&UNK)){
+                    *pair_counts.entry((pair[0], pair[1])).or_default() += count;
+                }
+            }
+            // Ties go to the lowest pair so the same corpus always learns the same merges
+            let Some((pair, count)) = pair_counts.into_iter().max_by(|a, b|a.1.cmp(&b.1).then(b.0.cmp(&a.0))) else {break};
+            if count < 2{
+                break;
+            }
+
+            let token = tokenizer.push_merge(pair);
+            for (symbols, _) in &mut frames{
+                merge_pair(symbols, pair, token);
+            }
+        }
+        tokenizer
+    }
+
+    /// Rebuilds a tokenizer from the lines of its vocabulary file
+    pub fn from_merges(lines: &[String])->Result<Self, String>{
+        let mut tokenizer = Self::new();
+        for line in lines{
+            let pair = line.split_once(' ')
+                .and_then(|(left, right)|Some((tokenizer.vocabulary.index(&unescape(left))?, tokenizer.vocabulary.index(&unescape(right))?)))
+                .ok_or_else(||format!("Malformed BPE merge: {}", line))?;
+            tokenizer.push_merge(pair);
+        }
+        Ok(tokenizer)
+    }
+
+    /// Lines of the vocabulary file, one per merge
+    pub fn merge_lines(&self)->Vec<String>{
+        let mut merges: Vec<(&(usize, usize), &usize)> = self.merges.iter().collect();
+        merges.sort_by_key(|(_, token)|**token);
+        merges.into_iter()
+            .map(|((left, right), _)|format!("{} {}", escape(&self.vocabulary.tokens()[*left]), escape(&self.vocabulary.tokens()[*right])))
+            .collect()
+    }
+
+    pub fn save(&self, path: &Path)->io::Result<()>{
+        fs::write(path, self.merge_lines().join("\n") + "\n")
+    }
+
+    fn push_merge(&mut self, pair: (usize, usize))->usize{
+        let tokens = self.vocabulary.tokens();
+        let token = self.vocabulary.push(format!("{}{}", tokens[pair.0], tokens[pair.1]));
+        self.merges.insert(pair, token);
+        token
+    }
+
+    fn base_tokens(&self, text: &str)->Vec<usize>{
+        text.chars().map(|char|self.base.get(&char).copied().unwrap_or(UNK)).collect()
+    }
+
+    /// Applies the merges to one frame, earliest learned first as when learning them
+    fn encode_frame(&self, frame: &str)->Vec<usize>{
+        let mut symbols = self.base_tokens(frame);
+        while let Some((pair, token)) = symbols.windows(2)
+            .filter_map(|pair|Some(((pair[0], pair[1]), *self.merges.get(&(pair[0], pair[1]))?)))
+            .min_by_key(|(_, token)|*token)
+        {
+            merge_pair(&mut symbols, pair, token);
+        }
+        symbols
+    }
+}
+impl Tokenizer for BpeTokenizer{
+    fn vocabulary(&self)->&Vocabulary{
+        &self.vocabulary
+    }
+    fn encode_body(&self, body: &str)->Vec<usize>{
+        // Songs repeat the same few frames over and over
+        let mut cache: HashMap<&str, Vec<usize>> = HashMap::new();
+        let mut tokens = Vec::new();
+        for frame in body.split_inclusive(' '){
+            tokens.extend(cache.entry(frame).or_insert_with(||self.encode_frame(frame)).iter());
+        }
+        tokens
+    }
+}
+
+/// Replaces every non overlapping occurrence of `pair`, left to right
+fn merge_pair(symbols: &mut Vec<usize>, pair: (usize, usize), token: usize){
+    let mut merged = Vec::with_capacity(symbols.len());
+    let mut index = 0;
+    while index < symbols.len(){
+        if index + 1 < symbols.len() && (symbols[index], symbols[index + 1]) == pair{
+            merged.push(token);
+            index += 2;
+        }else{
+            merged.push(symbols[index]);
+            index += 1;
+        }
+    }
+    *symbols = merged;
+}
+
+fn escape(token: &str)->String{
+    token.chars().map(|char|match char{
+        ' ' => "\\s".to_string(),
+        BAR_MARKER => "\\n".to_string(),
+        BEAT_MARKER => "\\t".to_string(),
+        '\\' => "\\\\".to_string(),
+        _ => char.to_string()
+    }).collect()
+}
+
+fn unescape(token: &str)->String{
+    let mut out = String::new();
+    let mut chars = token.chars();
+    while let Some(char) = chars.next(){
+        if char != '\\'{
+            out.push(char);
+            continue;
+        }
+        match chars.next(){
+            Some('s') => out.push(' '),
+            Some('n') => out.push(BAR_MARKER),
+            Some('t') => out.push(BEAT_MARKER),
+            Some(other) => out.push(other),
+            None => out.push('\\')
+        }
+    }
+    out
+}
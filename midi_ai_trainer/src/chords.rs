@@ -0,0 +1,92 @@
+/*
+    One token per chord for .cary text
+
+    Every frame whose pitches form a chord of the vocabulary becomes a single token, closing space
+    included. Rarer chords fall back to a token per character, as do meter markers and repeat tokens.
+    The vocabulary file lists the chords, most frequent first, one per line as their pitch characters.
+*/
+
+use std::{collections::HashMap, fs, io, path::Path};
+use smai_config::format::{repeat_power, BAR_MARKER, BEAT_MARKER};
+use crate::{strip_cary_header, tokenizer::{cary_alphabet, Vocabulary, UNK}, Tokenizer};
+
+pub struct ChordTokenizer{
+    vocabulary: Vocabulary, // The single characters, then a token per chord with its space
+    characters: HashMap<char, usize>,
+    chords: HashMap<String, usize> // Pitch characters of a chord and its token
+}
+impl ChordTokenizer{
+    /// Chords of the songs sounding in at least `min_count` frames, the `max_chords` most frequent
+    pub fn learn(songs: &[String], min_count: usize, max_chords: usize)->Self{
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for song in songs{
+            for frame in strip_cary_header(song).split_terminator(' '){
+                let pitches = split_frame(frame).1;
+                // Single pitches are already a token each
+                if pitches.chars().count() > 1{
+                    *counts.entry(pitches).or_default() += 1;
+                }
+            }
+        }
+
+        let mut chords: Vec<(&str, usize)> = counts.into_iter().filter(|(_, count)|*count >= min_count).collect();
+        chords.sort_by(|a, b|b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        chords.truncate(max_chords);
+        let lines: Vec<String> = chords.into_iter().map(|(chord, _)|chord.to_string()).collect();
+        Self::from_chords(&lines)
+    }
+
+    /// Rebuilds a tokenizer from the lines of its vocabulary file
+    pub fn from_chords(lines: &[String])->Self{
+        let mut vocabulary = Vocabulary::new(cary_alphabet().map(String::from));
+        let characters = cary_alphabet().filter_map(|char|Some((char, vocabulary.index(&char.to_string())?))).collect();
+        let mut chords = HashMap::new();
+        for chord in lines{
+            chords.insert(chord.clone(), vocabulary.push(format!("{} ", chord)));
+        }
+        Self{vocabulary, characters, chords}
+    }
+
+    /// Lines of the vocabulary file, one per chord
+    pub fn chord_lines(&self)->Vec<String>{
+        let mut chords: Vec<(&String, &usize)> = self.chords.iter().collect();
+        chords.sort_by_key(|(_, token)|**token);
+        chords.into_iter().map(|(chord, _)|chord.clone()).collect()
+    }
+
+    pub fn save(&self, path: &Path)->io::Result<()>{
+        fs::write(path, self.chord_lines().join("\n") + "\n")
+    }
+
+    fn push_characters(&self, tokens: &mut Vec<usize>, text: &str){
+        tokens.extend(text.chars().map(|char|self.characters.get(&char).copied().unwrap_or(UNK)));
+    }
+}
+impl Tokenizer for ChordTokenizer{
+    fn vocabulary(&self)->&Vocabulary{
+        &self.vocabulary
+    }
+    fn encode_body(&self, body: &str)->Vec<usize>{
+        let mut tokens = Vec::new();
+        for frame in body.split_inclusive(' '){
+            let Some(frame) = frame.strip_suffix(' ') else {
+                // Text after the last frame
+                self.push_characters(&mut tokens, frame);
+                continue;
+            };
+            let (prefix, pitches) = split_frame(frame);
+            self.push_characters(&mut tokens, prefix);
+            match self.chords.get(pitches){
+                Some(chord) => tokens.push(*chord),
+                None => self.push_characters(&mut tokens, &format!("{} ", pitches))
+            }
+        }
+        tokens
+    }
+}
+
+/// Meter markers and repeat tokens at the start of a frame, and its pitches
+fn split_frame(frame: &str)->(&str, &str){
+    let pitches = frame.trim_start_matches(|char|char == BAR_MARKER || char == BEAT_MARKER || repeat_power(char).is_some());
+    (&frame[..frame.len() - pitches.len()], pitches)
+}
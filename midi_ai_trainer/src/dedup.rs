@@ -0,0 +1,283 @@
+/*
+    Near-duplicate detection for a directory of .cary files
+
+    Every piece is reduced to its chord changes, each written relative to the chord before it,
+    so transposed and re-timed copies give the same sequence. Runs of SHINGLE_SIZE changes are
+    hashed into shingles, and a MinHash signature estimates the Jaccard similarity of two
+    pieces' shingle sets. Signatures are split into bands so only pieces sharing a band are compared.
+
+    The quantization grid is not normalised: a coarser grid drops short notes and merges near
+    chords, so a copy compressed with another compressor.quantization shares far fewer chord changes.
+*/
+
+use std::{collections::{BTreeMap, BTreeSet, HashMap, HashSet}, fs, io, path::{Path, PathBuf}};
+use smai_config::hash::{fnv1a, FNV_OFFSET};
+use crate::{header_value, strip_cary_header};
+
+const SHINGLE_SIZE: usize = 4; // Chord changes per shingle
+const BANDS: usize = 32; // Pieces sharing a band are compared, two rows each still finds most pairs above 0.3
+const ROWS_PER_BAND: usize = 2;
+const SIGNATURE_SIZE: usize = BANDS * ROWS_PER_BAND;
+
+/// Every variant the compressor wrote for one source file
+pub struct Piece{
+    pub source: String,
+    pub files: Vec<PathBuf>,
+    pub songs: Vec<String>
+}
+
+/// A piece close enough to an earlier one to be left out
+pub struct Duplicate{
+    pub piece: usize,
+    pub original: usize,
+    pub similarity: f32
+}
+
+/// Reads every .cary file of a directory, grouping variants by the %source of their header
+pub fn load_pieces(dir: &Path)->io::Result<Vec<Piece>>{
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)?{
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension|extension == "cary"){
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut pieces: BTreeMap<String, Piece> = BTreeMap::new();
+    for path in paths{
+        let song = fs::read_to_string(&path)?;
+        // Files without a header are their own piece
+        let source = header_value(&song, "source").unwrap_or_else(||path.file_name().unwrap_or_default().to_string_lossy().into_owned());
+        let piece = pieces.entry(source.clone()).or_insert_with(||Piece{source, files: Vec::new(), songs: Vec::new()});
+        piece.files.push(path);
+        piece.songs.push(song);
+    }
+    Ok(pieces.into_values().collect())
+}
+
+/// Pieces at least `threshold` similar to one that is kept, each matched against the most similar one.
+/// Pieces with more files are kept first, so a stray copy never outlives the full set of variants,
+/// and between pieces with as many files the first in `pieces` is kept.
+pub fn find_duplicates(pieces: &[Piece], threshold: f32)->Vec<Duplicate>{
+    let mut duplicates = Vec::new();
+    let mut kept: Vec<(usize, [u64; SIGNATURE_SIZE])> = Vec::new(); // Piece index and signature
+    let mut buckets: HashMap<(usize, u64), Vec<usize>> = HashMap::new();
+
+    let mut order: Vec<usize> = (0..pieces.len()).collect();
+    order.sort_by_key(|index|std::cmp::Reverse(pieces[*index].files.len()));
+    for index in order{
+        let piece = &pieces[index];
+        // Variants only differ in transposition, timing and cropping, one stands for them all
+        let Some(signature) = piece.songs.first().and_then(|song|signature(song)) else {continue};
+
+        let mut candidates = HashSet::new();
+        for (band, key) in band_keys(&signature).enumerate(){
+            candidates.extend(buckets.get(&(band, key)).into_iter().flatten().copied());
+        }
+        let best = candidates
+            .into_iter()
+            .map(|candidate: usize|(kept[candidate].0, similarity(&signature, &kept[candidate].1)))
+            .max_by(|a, b|a.1.total_cmp(&b.1).then(b.0.cmp(&a.0)));
+        if let Some((original, similarity)) = best.filter(|(_, similarity)|*similarity >= threshold){
+            duplicates.push(Duplicate{piece: index, original, similarity});
+            continue;
+        }
+
+        for (band, key) in band_keys(&signature).enumerate(){
+            buckets.entry((band, key)).or_default().push(kept.len());
+        }
+        kept.push((index, signature));
+    }
+    duplicates
+}
+
+/// Pitches sounding in each frame, ascending
+fn chords(song: &str)->Vec<Vec<i32>>{
+    match header_value(song, "encoding").as_deref(){
+        Some("events") => return event_chords(song),
+        Some("remi") => return remi_chords(song),
+        _ => ()
+    }
+    strip_cary_header(song)
+        .split_terminator(' ')
+        // Meter markers and repeat tokens are not pitches
+        .map(|frame|frame.chars().filter(|char|('!'..='~').contains(char)).map(|char|char as i32).collect())
+        .collect()
+}
+
+/// Pitches sounding before each time shift of the events encoding
+fn event_chords(song: &str)->Vec<Vec<i32>>{
+    let mut chords = Vec::new();
+    let mut sounding = BTreeSet::new();
+    for word in strip_cary_header(song).split_whitespace(){
+        if let Some(pitch) = word.strip_prefix("on").and_then(|pitch|pitch.parse::<i32>().ok()){
+            sounding.insert(pitch);
+        }else if let Some(pitch) = word.strip_prefix("off").and_then(|pitch|pitch.parse::<i32>().ok()){
+            sounding.remove(&pitch);
+        }else if word.starts_with('t'){
+            chords.push(sounding.iter().copied().collect());
+        }
+    }
+    chords
+}
+
+/// Pitches struck at each position of the REMI encoding
+fn remi_chords(song: &str)->Vec<Vec<i32>>{
+    let mut chords: Vec<Vec<i32>> = Vec::new();
+    for word in strip_cary_header(song).split_whitespace(){
+        if word.starts_with("pos"){
+            chords.push(Vec::new());
+        }else if let (Some(pitch), Some(chord)) = (word.strip_prefix("pitch").and_then(|pitch|pitch.parse().ok()), chords.last_mut()){
+            chord.push(pitch);
+        }
+    }
+    chords.iter_mut().for_each(|chord|chord.sort());
+    chords
+}
+
+/// Chord changes of a piece, each hashed from its lowest pitch's step from the previous chord and its own intervals
+fn chord_changes(song: &str)->Vec<u64>{
+    let mut changes = Vec::new();
+    let mut previous: Option<Vec<i32>> = None;
+
+    for chord in chords(song){
+        // Rests and held chords are skipped, so tempo and note lengths don't matter
+        if chord.is_empty() || previous.as_ref() == Some(&chord){
+            continue;
+        }
+
+        let step = previous.as_ref().map_or(0, |previous|chord[0] - previous[0]);
+        let mut hash = fnv1a(FNV_OFFSET, &step.to_le_bytes());
+        for pitch in &chord[1..]{
+            hash = fnv1a(hash, &(pitch - chord[0]).to_le_bytes());
+        }
+        changes.push(hash);
+        previous = Some(chord);
+    }
+    changes
+}
+
+/// MinHash signature over the piece's shingles, None for pieces without notes
+fn signature(song: &str)->Option<[u64; SIGNATURE_SIZE]>{
+    let changes = chord_changes(song);
+    if changes.is_empty(){
+        return None;
+    }
+
+    let mut signature = [u64::MAX; SIGNATURE_SIZE];
+    for shingle in changes.windows(SHINGLE_SIZE.min(changes.len())){
+        let shingle_hash = shingle.iter().fold(FNV_OFFSET, |hash, change|fnv1a(hash, &change.to_le_bytes()));
+        for (seed, minimum) in signature.iter_mut().enumerate(){
+            *minimum = (*minimum).min(mix(shingle_hash ^ mix(seed as u64 + 1)));
+        }
+    }
+    Some(signature)
+}
+
+fn band_keys(signature: &[u64; SIGNATURE_SIZE])->impl Iterator<Item=u64> + '_{
+    signature.chunks(ROWS_PER_BAND).map(|rows|rows.iter().fold(FNV_OFFSET, |hash, row|fnv1a(hash, &row.to_le_bytes())))
+}
+
+/// Share of matching signature slots, an estimate of the Jaccard similarity of the shingle sets
+fn similarity(a: &[u64; SIGNATURE_SIZE], b: &[u64; SIGNATURE_SIZE])->f32{
+    a.iter().zip(b).filter(|(a, b)|a == b).count() as f32 / SIGNATURE_SIZE as f32
+}
+
+/// SplitMix64 finalizer, spreads one hash into an independent looking one per seed
+fn mix(mut x: u64)->u64{
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    /// Plain .cary text of `length` made-up chords, each held for `hold` frames
+    fn song(seed: u64, length: usize, transposition: u8, hold: usize)->String{
+        let mut state = seed;
+        let mut next = |range: u64|{
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 33) % range
+        };
+        let mut out = String::new();
+        for _ in 0..length{
+            let root = b'<' + next(24) as u8 + transposition;
+            let chord: String = [0, 4 + next(2) as u8, 7].iter().take(1 + next(3) as usize).map(|interval|(root + interval) as char).collect();
+            for _ in 0..hold{
+                out += &chord;
+                out.push(' ');
+            }
+        }
+        out
+    }
+
+    fn piece(source: &str, files: usize, song: &str)->Piece{
+        Piece{source: source.to_string(), files: vec![PathBuf::from(source); files], songs: vec![song.to_string(); files]}
+    }
+
+    #[test]
+    fn chord_changes_ignore_key_and_note_lengths(){
+        let original = song(1, 200, 0, 1);
+        assert!(!chord_changes(&original).is_empty());
+        assert_eq!(chord_changes(&song(1, 200, 5, 3)), chord_changes(&original));
+        // Rests don't count either
+        assert_eq!(chord_changes(&original.replace(' ', "  ")), chord_changes(&original));
+    }
+
+    #[test]
+    fn chord_changes_hash_the_step_and_the_intervals(){
+        let changes = chord_changes("<CH <CH @ <CH <CG ");
+        assert_eq!(changes.len(), 4);
+        // A step up by four and back down are different changes, as are a major and a minor chord on the same root
+        assert_ne!(changes[1], changes[2]);
+        assert_ne!(changes[2], changes[3]);
+        assert_eq!(chord_changes("<CH @ <CH "), changes[..3]);
+    }
+
+    #[test]
+    fn signatures_estimate_similarity(){
+        let original = signature(&song(1, 300, 0, 1)).unwrap();
+        assert_eq!(similarity(&original, &signature(&song(1, 300, 7, 2)).unwrap()), 1.0);
+
+        let unrelated = signature(&song(2, 300, 0, 1)).unwrap();
+        assert!(similarity(&original, &unrelated) < 0.2);
+
+        // Half of the chords in common share about a third of the shingles
+        let half = song(1, 150, 0, 1) + &song(3, 150, 0, 1);
+        let shared = similarity(&original, &signature(&half).unwrap());
+        assert!((0.2..0.5).contains(&shared), "{}", shared);
+        assert_eq!(signature(""), None);
+    }
+
+    #[test]
+    fn similar_signatures_share_bands(){
+        let original = signature(&song(1, 300, 0, 1)).unwrap();
+        let keys: Vec<u64> = band_keys(&original).collect();
+        assert_eq!(keys.len(), BANDS);
+        assert_eq!(band_keys(&signature(&song(1, 300, 2, 1)).unwrap()).collect::<Vec<_>>(), keys);
+
+        let unrelated = signature(&song(2, 300, 0, 1)).unwrap();
+        assert!(band_keys(&unrelated).zip(&keys).filter(|(a, b)|a == *b).count() < 2);
+    }
+
+    #[test]
+    fn the_piece_with_more_files_is_kept(){
+        let pieces = [
+            piece("copy.csv", 1, &song(1, 300, 0, 1)),
+            piece("other.csv", 2, &song(2, 300, 0, 1)),
+            piece("t808.csv", 12, &song(1, 300, 3, 2)),
+            piece("t808_again.csv", 12, &song(1, 300, 0, 1))
+        ];
+        let duplicates: Vec<(usize, usize)> = find_duplicates(&pieces, 0.8).iter().map(|duplicate|(duplicate.piece, duplicate.original)).collect();
+        assert_eq!(duplicates, [(3, 2), (0, 2)]);
+    }
+
+    #[test]
+    fn pieces_without_notes_are_never_duplicates(){
+        let pieces = [piece("silent.csv", 1, "   "), piece("also_silent.csv", 1, "   ")];
+        assert!(find_duplicates(&pieces, 0.0).is_empty());
+    }
+}
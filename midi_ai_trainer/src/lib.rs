@@ -0,0 +1,843 @@
+/*
+    A recurrent network over token indices, see tokenizer.rs for how songs become tokens
+
+    Neural net function
+    Token + Hidden state -> Output + Next hidden state
+    The token picks its row of the embedding, which is read together with the previous hidden state.
+    Every layer is dense with a sigmoid. The last one scores every token of the vocabulary, and those
+    scores are also the next hidden state, which starts out as zeros.
+
+    Training
+    Every token of a window is trained to predict the one after it. The last truncate_steps steps of the
+    window update the layers and the embedding, without following the hidden state back to earlier steps.
+
+    Loss Function
+    Squared error against the one hot of the token that comes next in the song
+    If the next token is the first and the model predicts [.8, .1, .01] then loss = .2² + .1² + .01²
+*/
+
+use std::{collections::HashMap, f32::consts::E, fmt::Display, fs, ops::RangeInclusive, path::{Path, PathBuf}};
+use clap::Args;
+use rand::{self, rngs::ThreadRng, Rng};
+use serde::{Deserialize, Serialize};
+use smai_config::{format::{repeat_power, repeat_tokens, BAR_MARKER, BEAT_MARKER}, ConfigArgs, Section};
+
+pub mod bpe;
+pub mod chords;
+pub mod dedup;
+pub mod tokenizer;
+
+pub use tokenizer::{CharTokenizer, Tokenizer, TokenizerKind, Vocabulary};
+
+pub const INPUT_FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../data/input/cary/t808.csv_0.cary");
+pub const CHECKPOINT_FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../checkpoints/saved_net");
+
+const EPOCHS: usize = 10;
+const LEARNING_RATE: f32 = 0.01;
+const TOKENIZER: TokenizerKind = TokenizerKind::Characters;
+const BPE_VOCABULARY_SIZE: usize = 512; // Tokens a BPE vocabulary is learned up to, when the input has none yet
+const CHORD_MIN_COUNT: usize = 2; // Frames a chord must sound in to get its own token
+const CHORD_VOCABULARY_SIZE: usize = 1024; // Most chords kept, the most frequent ones
+const EMBEDDING_SIZE: usize = 32; // Floats a token's input is, learned per token
+const HIDDEN_LAYERS: [usize; 1] = [111];
+const WINDOW_SIZE: usize = 100;
+const MIN_WINDOW_SIZE: usize = 100;
+const WINDOW_STEP: usize = 1;
+const TRUNCATE_STEPS: usize = 5; // How many steps back we propagate
+
+// On the fly augmentation, an alternative to having the compressor write every variant to disk
+const AUGMENT_ON_THE_FLY: bool = false;
+const AUGMENT_TRANSPOSITIONS: (i32, i32) = (-6, 5);
+const AUGMENT_TEMPO_STRETCHES: [f32; 1] = [1.0];
+const AUGMENT_CROP_FRAMES: Option<usize> = None;
+
+// Pieces of a training directory this similar to an earlier one are left out, see dedup.rs
+const DEDUP: bool = true;
+const DEDUP_THRESHOLD: f32 = 0.8;
+
+/// The [trainer] section: the tokenizer and network shape for new checkpoints, how songs are cut into
+/// windows and augmented, and which near-duplicate pieces are left out
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrainerConfig{
+    pub epochs: usize,
+    pub learning_rate: f32,
+    pub tokenizer: TokenizerKind, // Must match the checkpoint's, if there is one
+    pub vocabulary: Option<PathBuf>, // For tokenizers with a vocabulary file, found next to the input when unset
+    pub bpe_vocabulary_size: usize,
+    pub chord_min_count: usize,
+    pub chord_vocabulary_size: usize,
+    pub embedding_size: usize, // Only used when there is no checkpoint to resume from
+    pub hidden_layers: Vec<usize>, // Likewise
+    pub window_size: usize,
+    pub min_window_size: usize,
+    pub window_step: usize,
+    pub truncate_steps: usize,
+    pub augment_on_the_fly: bool,
+    pub augment_transpositions: (i32, i32),
+    pub augment_tempo_stretches: Vec<f32>,
+    pub augment_crop_frames: Option<usize>,
+    pub dedup: bool,
+    pub dedup_threshold: f32
+}
+impl Default for TrainerConfig{
+    fn default()->Self{
+        Self{
+            epochs: EPOCHS,
+            learning_rate: LEARNING_RATE,
+            tokenizer: TOKENIZER,
+            vocabulary: None,
+            bpe_vocabulary_size: BPE_VOCABULARY_SIZE,
+            chord_min_count: CHORD_MIN_COUNT,
+            chord_vocabulary_size: CHORD_VOCABULARY_SIZE,
+            embedding_size: EMBEDDING_SIZE,
+            hidden_layers: HIDDEN_LAYERS.to_vec(),
+            window_size: WINDOW_SIZE,
+            min_window_size: MIN_WINDOW_SIZE,
+            window_step: WINDOW_STEP,
+            truncate_steps: TRUNCATE_STEPS,
+            augment_on_the_fly: AUGMENT_ON_THE_FLY,
+            augment_transpositions: AUGMENT_TRANSPOSITIONS,
+            augment_tempo_stretches: AUGMENT_TEMPO_STRETCHES.to_vec(),
+            augment_crop_frames: AUGMENT_CROP_FRAMES,
+            dedup: DEDUP,
+            dedup_threshold: DEDUP_THRESHOLD
+        }
+    }
+}
+impl Section for TrainerConfig{
+    const NAME: &'static str = "trainer";
+
+    fn validate(&self)->Result<(), String>{
+        if self.learning_rate <= 0.0 || self.learning_rate.is_nan(){
+            return Err("learning_rate must be positive".to_string());
+        }
+        if self.embedding_size == 0{
+            return Err("embedding_size must be positive".to_string());
+        }
+        if self.hidden_layers.contains(&0){
+            return Err("hidden_layers can't have empty layers".to_string());
+        }
+        // Every token but the last is trained to predict the one after it
+        if self.window_size < 2{
+            return Err("window_size must be at least 2".to_string());
+        }
+        if self.window_step == 0{
+            return Err("window_step must be positive".to_string());
+        }
+        if self.min_window_size > self.window_size{
+            return Err("min_window_size can't exceed window_size".to_string());
+        }
+        if self.augment_transpositions.0 > self.augment_transpositions.1{
+            return Err("augment_transpositions must list the lowest first".to_string());
+        }
+        if self.augment_tempo_stretches.is_empty() || self.augment_tempo_stretches.iter().any(|stretch|*stretch <= 0.0){
+            return Err("augment_tempo_stretches must hold at least one positive factor".to_string());
+        }
+        if self.augment_crop_frames == Some(0){
+            return Err("augment_crop_frames must be positive".to_string());
+        }
+        let base_size = tokenizer::SPECIAL_TOKENS.len() + tokenizer::CARY_ALPHABET_SIZE;
+        if self.bpe_vocabulary_size < base_size{
+            return Err(format!("bpe_vocabulary_size must be at least {}, the tokens BPE starts from", base_size));
+        }
+        if self.chord_min_count == 0{
+            return Err("chord_min_count must be positive".to_string());
+        }
+        if self.augment_on_the_fly && !self.tokenizer.reads(None){
+            return Err(format!("augment_on_the_fly only works on .cary characters, not with the {} tokenizer", self.tokenizer.name()));
+        }
+        if !(0.0..=1.0).contains(&self.dedup_threshold){
+            return Err("dedup_threshold must be between 0 and 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+pub struct TrainArgs{
+    /// .cary file to train on, or a directory of them
+    #[arg(default_value = INPUT_FILE)]
+    pub input: PathBuf,
+    /// Network to resume from and save to after every epoch
+    #[arg(long, default_value = CHECKPOINT_FILE)]
+    pub checkpoint: PathBuf,
+    /// Replaces trainer.epochs from the configuration
+    #[arg(long)]
+    pub epochs: Option<usize>,
+    /// Replaces trainer.learning_rate from the configuration
+    #[arg(long)]
+    pub learning_rate: Option<f32>,
+    #[command(flatten)]
+    pub config: ConfigArgs
+}
+
+pub fn run(args: &TrainArgs)->std::io::Result<()>{
+    let mut config: TrainerConfig = args.config.load()?;
+    config.epochs = args.epochs.unwrap_or(config.epochs);
+    config.learning_rate = args.learning_rate.unwrap_or(config.learning_rate);
+
+    let songs = load_songs(&args.input, &config)?;
+    if let Some(song) = songs.iter().find(|song|!config.tokenizer.reads(header_value(song, "encoding").as_deref())){
+        let encoding = header_value(song, "encoding").unwrap_or_else(||"plain".to_string());
+        let message = format!("The {} tokenizer can't read files with the {} encoding", config.tokenizer.name(), encoding);
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message));
+    }
+
+    let checkpoint = load_net(&args.checkpoint);
+    if let Some(net) = checkpoint.as_ref().filter(|net|net.tokenizer() != config.tokenizer){
+        let message = format!("The checkpoint was trained with the {} tokenizer, not {}", net.tokenizer().name(), config.tokenizer.name());
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, message));
+    }
+    // A checkpoint from an older version can't be trained further, and saving over it would lose it
+    if let Some(Err(e)) = checkpoint.as_ref().map(|net|net.build_tokenizer()){
+        let message = format!("{}; delete {} or pass another --checkpoint to train a new network", e, args.checkpoint.display());
+        return Err(std::io::Error::new(e.kind(), message));
+    }
+    let mut net = match checkpoint{
+        Some(net) => net,
+        None => create_network(config.tokenizer, load_vocabulary(&args.input, &songs, &config)?, &config)?
+    };
+    let mut rng = rand::rng();
+    let tokenizer = net.build_tokenizer()?;
+    let augmentation = Augmentation::new(&config);
+
+    let mut batches: Vec<_> = songs.iter().flat_map(|song|batchify(tokenizer.as_ref(), song, &config)).collect();
+    if batches.is_empty(){
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Input is too short to make a single batch"));
+    }
+    
+    // Training loop
+    for epoch in 0..config.epochs {
+        println!("Epoch {}", epoch);
+        if config.augment_on_the_fly {
+            batches = songs.iter().flat_map(|song|batchify(tokenizer.as_ref(), &augmentation.apply(song, &mut rng), &config)).collect();
+        }
+        train_network(&mut net, &batches, &config);
+        
+        // Calculate validation loss if you have validation data
+        let val_loss = calculate_loss_of_batch(&net, &batches[0]);
+        println!("Epoch {} - Validation Loss: {:.6}", epoch, val_loss);
+        save_net(&net, &args.checkpoint);
+    }
+    Ok(())
+}
+
+/// Vocabulary of the configured tokenizer, empty for tokenizers without a vocabulary file.
+/// BPE and chord vocabularies are learned from the songs and saved the first time.
+fn load_vocabulary(input: &Path, songs: &[String], config: &TrainerConfig)->std::io::Result<Vec<String>>{
+    let Some(file_name) = config.tokenizer.vocabulary_file() else {return Ok(Vec::new())};
+    let path = match &config.vocabulary{
+        Some(path) => path.clone(),
+        None if input.is_dir() => input.join(file_name),
+        None => input.with_file_name(file_name)
+    };
+    if path.exists(){
+        return tokenizer::load_vocabulary(&path);
+    }
+    match config.tokenizer{
+        TokenizerKind::Bpe => {
+            let bpe = bpe::BpeTokenizer::learn(songs, config.bpe_vocabulary_size);
+            bpe.save(&path)?;
+            println!("Learned {} BPE tokens, saved to {}", bpe.vocabulary_size(), path.display());
+            Ok(bpe.merge_lines())
+        }
+        TokenizerKind::Chords => {
+            let chords = chords::ChordTokenizer::learn(songs, config.chord_min_count, config.chord_vocabulary_size);
+            chords.save(&path)?;
+            println!("Learned {} chord tokens, saved to {}", chords.chord_lines().len(), path.display());
+            Ok(chords.chord_lines())
+        }
+        // The other tokenizers have no vocabulary file
+        _ => Ok(Vec::new())
+    }
+}
+
+/// Reads one .cary file, or every one of a directory leaving out near-duplicate pieces
+fn load_songs(input: &Path, config: &TrainerConfig)->std::io::Result<Vec<String>>{
+    if !input.is_dir(){
+        return Ok(vec![fs::read_to_string(input)?]);
+    }
+
+    let mut pieces = dedup::load_pieces(input)?;
+    if config.dedup{
+        let duplicates = dedup::find_duplicates(&pieces, config.dedup_threshold);
+        for duplicate in &duplicates{
+            println!("Skipping {}, {:.0}% similar to {}", pieces[duplicate.piece].source, 100.0 * duplicate.similarity, pieces[duplicate.original].source);
+        }
+        for duplicate in duplicates.iter().rev(){
+            pieces.remove(duplicate.piece);
+        }
+    }
+    Ok(pieces.into_iter().flat_map(|piece|piece.songs).collect())
+}
+
+pub fn save_net(net: &Network, path: &Path){
+    let Ok(string) = serde_json::to_string(net) else {println!("Failed to save"); return;};
+    let Ok(_) = fs::write(path, string) else {println!("Failed to save"); return;};
+}
+pub fn load_net(path: &Path)->Option<Network>{
+    let Ok(string) = fs::read_to_string(path) else {println!("Failed to Load"); return None};
+    let Ok(net) = serde_json::from_str::<Network>(&string) else {println!("Failed to Load"); return None};
+    Some(net)
+}
+
+
+
+fn create_network(tokenizer: TokenizerKind, vocabulary: Vec<String>, config: &TrainerConfig)->std::io::Result<Network>{
+    let mut rng = rand::rng();
+    let vocabulary_size = tokenizer.build(&vocabulary)?.vocabulary_size();
+    // A token's embedding, then the hidden state, which is as long as the output
+    let layer_sizes: Vec<usize> = [config.embedding_size + vocabulary_size].into_iter()
+        .chain(config.hidden_layers.iter().copied())
+        .chain([vocabulary_size])
+        .collect();
+
+    Ok(Network::new_random(&mut rng, &layer_sizes, config.embedding_size, tokenizer, vocabulary))
+}
+
+fn train_network(net: &mut Network, batches: &[Vec<usize>], config: &TrainerConfig) {
+    for (batch_idx, batch) in batches.iter().enumerate() {
+        // Forward pass to calculate loss
+        let mut total_loss = 0.0;
+        let mut hidden_state = Vector::zeros(net.vocabulary_size());
+        
+        for step in batch.windows(2) {
+            let (output, new_hidden) = net.forward(step[0], &hidden_state);
+            hidden_state = new_hidden;
+            
+            // Calculate and accumulate loss for this time step, against the token that follows
+            total_loss += calculate_loss_of_one_iteration(&output, step[1]);
+        }
+        
+        // Print loss before backpropagation
+        let avg_loss = total_loss / (batch.len() - 1) as f32;
+        println!("Batch {} - Loss: {:.6}", batch_idx, avg_loss);
+        
+        // Perform backpropagation
+        train_from_loss(net, batch, config.learning_rate, config.truncate_steps);
+    }
+}
+
+
+fn train_from_loss(net: &mut Network, batch: &[usize], learning_rate: f32, truncate_steps: usize) {
+    struct NodeGradient {
+        weight_gradients: Vec<f32>,
+        bias_gradient: f32,
+    }
+
+    // Forward pass: store all activations for BPTT
+    let mut all_activations = Vec::new();
+    let mut all_hidden_states = Vec::new();
+    let mut hidden_state = Vector::zeros(net.vocabulary_size());
+
+    // The last token has nothing after it to predict
+    for &token in &batch[..batch.len() - 1] {
+        let input = net.input(token, &hidden_state);
+        let (output, new_hidden) = net.forward_input(input.clone());
+        
+        all_activations.push((input, output.clone()));
+        all_hidden_states.push(hidden_state.clone());
+        hidden_state = new_hidden;
+    }
+
+    // Backward pass (BPTT)
+    let mut gradients: Vec<Vec<NodeGradient>> = net.layers.iter()
+        .map(|layer| {
+            layer.nodes.iter()
+                .map(|_| NodeGradient {
+                    weight_gradients: vec![0.0; layer.nodes[0].input_weights.0.len()],
+                    bias_gradient: 0.0,
+                })
+                .collect()
+        })
+        .collect();
+    // Only the rows of the tokens in the batch get one
+    let mut embedding_gradients: HashMap<usize, Vec<f32>> = HashMap::new();
+
+    // We'll do BPTT with a truncated window (simplified)
+    let seq_len = all_activations.len();
+
+    for t in (0..seq_len).rev() {
+        let (input, output) = &all_activations[t];
+        let target = batch[t + 1];
+        
+        // Calculate output error, against a one hot of the target token
+        let error = output.0.iter()
+            .enumerate()
+            .map(|(i, o)| o - (i == target) as u8 as f32)
+            .collect::<Vec<f32>>();
+
+        // Activations of every layer, starting with its input
+        let mut activations = vec![input.clone()];
+        for layer in net.layers.iter() {
+            let next = layer.forward(&activations[activations.len() - 1]);
+            activations.push(next);
+        }
+
+        // Backpropagate through layers, `error` holding the error of the layer being updated
+        let mut error = error;
+        for layer_idx in (0..net.layers.len()).rev() {
+            let layer = &net.layers[layer_idx];
+            let layer_input = &activations[layer_idx];
+            let error_terms: Vec<f32> = error.iter()
+                .zip(activations[layer_idx + 1].0.iter())
+                .map(|(error, output)| error * output * (1.0 - output)) // Sigmoid derivative
+                .collect();
+
+            for (node_idx, error_term) in error_terms.iter().enumerate() {
+                // Update weight gradients
+                for (weight_idx, input_val) in layer_input.0.iter().enumerate() {
+                    gradients[layer_idx][node_idx].weight_gradients[weight_idx] += 
+                        error_term * input_val;
+                }
+
+                // Update bias gradient
+                gradients[layer_idx][node_idx].bias_gradient += error_term;
+            }
+
+            // Error of the layer's input, the previous layer's output
+            let mut input_error = vec![0.0; layer_input.0.len()];
+            for (node, error_term) in layer.nodes.iter().zip(&error_terms) {
+                for (input_error, weight) in input_error.iter_mut().zip(node.input_weights.0.iter()) {
+                    *input_error += error_term * weight;
+                }
+            }
+            error = input_error;
+        }
+
+        // The first layer's input starts with the embedding of the token
+        let row = embedding_gradients.entry(batch[t]).or_insert_with(|| vec![0.0; net.embedding.size()]);
+        for (gradient, error) in row.iter_mut().zip(&error) {
+            *gradient += error;
+        }
+
+        // Stop backpropagating if we've gone far enough back in time
+        if seq_len - t > truncate_steps {
+            break;
+        }
+    }
+
+    // Apply gradients
+    for (layer_idx, layer) in net.layers.iter_mut().enumerate() {
+        for (node_idx, node) in layer.nodes.iter_mut().enumerate() {
+            let grad = &gradients[layer_idx][node_idx];
+            
+            // Update weights
+            for (weight_idx, weight) in node.input_weights.0.iter_mut().enumerate() {
+                *weight -= learning_rate * grad.weight_gradients[weight_idx] / batch.len() as f32;
+            }
+            
+            // Update bias
+            node.input_bias -= learning_rate * grad.bias_gradient / batch.len() as f32;
+        }
+    }
+    for (token, gradient) in embedding_gradients {
+        net.embedding.update(token, &gradient, learning_rate / batch.len() as f32);
+    }
+}
+
+
+
+fn calculate_loss_of_batch(net: &Network, batch: &[usize])->f32{
+    let mut total_loss = 0.0;
+    let mut previous = Vector::zeros(net.vocabulary_size());
+    for step in batch.windows(2){
+        let (out, inner) = net.forward(step[0], &previous);
+        previous = inner;
+
+        total_loss += calculate_loss_of_one_iteration(&out, step[1])
+    }
+    total_loss
+}
+/// Squared error against the one hot of the `real` token
+fn calculate_loss_of_one_iteration(predicted: &Vector, real: usize)->f32{
+    predicted.0.iter().enumerate()
+        .fold(0.0, |fold, (index, predicted)|{
+            fold + (*predicted - (index == real) as u8 as f32).powi(2)
+        })
+}
+
+
+/// Windows of token indices over a song, the last one filled up with PAD
+fn batchify(tokenizer: &dyn Tokenizer, song: &str, config: &TrainerConfig) -> Vec<Vec<usize>> {
+    let sequence = tokenizer.encode(song);
+    let sequence_length = sequence.len();
+    
+    let mut batches = Vec::new();
+    let mut start = 0;
+
+    while start + config.window_size <= sequence_length {
+        let end = start + config.window_size;
+        batches.push(sequence[start..end].to_vec());
+        start += config.window_step;
+    }
+
+    // Handle remaining elements with padding
+    if sequence_length > start + config.min_window_size {
+        let mut final_batch = sequence[start..].to_vec();
+        final_batch.resize(config.window_size, tokenizer::PAD);
+        batches.push(final_batch);
+    }
+
+    batches
+}
+
+
+/// Skips the metadata block the compressor writes before the frames, if there is one
+fn strip_cary_header(song: &str)->&str{
+    if !song.starts_with("%cary "){
+        return song;
+    }
+    match song.find("\n%end\n"){
+        Some(end) => &song[end + "\n%end\n".len()..],
+        None => ""
+    }
+}
+
+/// Value of a `%key value` line of the .cary header
+fn header_value(song: &str, key: &str)->Option<String>{
+    if !song.starts_with("%cary "){
+        return None;
+    }
+    song.lines()
+        .take_while(|line|*line != "%end")
+        .find_map(|line|line.strip_prefix('%')?.strip_prefix(key)?.strip_prefix(' '))
+        .map(str::to_string)
+}
+
+/// Transposes, stretches and crops .cary text in memory, picking a random variant each call
+struct Augmentation{
+    transpositions: RangeInclusive<i32>,
+    tempo_stretches: Vec<f32>,
+    crop_frames: Option<usize>
+}
+impl Augmentation{
+    fn new(config: &TrainerConfig)->Self{
+        Self{
+            transpositions: config.augment_transpositions.0..=config.augment_transpositions.1,
+            tempo_stretches: config.augment_tempo_stretches.clone(),
+            crop_frames: config.augment_crop_frames
+        }
+    }
+    fn apply(&self, song: &str, rng: &mut ThreadRng)->String{
+        let transposition = rng.random_range(self.transpositions.clone());
+        let stretch = self.tempo_stretches[rng.random_range(0..self.tempo_stretches.len())];
+
+        // Every frame ends with a space and may start with meter markers
+        let run_length_encoded = strip_cary_header(song).chars().any(|char|repeat_power(char).is_some());
+        let frames = expand_repeats(strip_cary_header(song));
+        let length = (frames.len() as f32 * stretch).ceil() as usize;
+        let mut window = 0..length;
+        if let Some(crop_frames) = self.crop_frames.filter(|crop_frames|*crop_frames < length){
+            let start = rng.random_range(0..=length - crop_frames);
+            window = start..start + crop_frames;
+        }
+
+        let mut out = String::new();
+        for frame in window{
+            let source = ((frame as f32 / stretch) as usize).min(frames.len() - 1);
+            let repeated = frame > 0 && ((frame - 1) as f32 / stretch) as usize == source;
+            for char in frames[source].chars(){
+                match char {
+                    // A repeated frame continues the bar or beat it started in
+                    BAR_MARKER | BEAT_MARKER => if !repeated {out.push(char)},
+                    _ => {
+                        let shifted = char as i32 + transposition;
+                        if (33..=126).contains(&shifted){
+                            out.push(shifted as u8 as char);
+                        }
+                    }
+                }
+            }
+            out.push(' ');
+        }
+        if run_length_encoded {encode_repeats(&out)} else {out}
+    }
+}
+
+/// Frames of run-length encoded .cary text written out in full, repeated frames leave out meter markers
+fn expand_repeats(song: &str)->Vec<String>{
+    let mut frames: Vec<String> = Vec::new();
+    for frame in song.split_terminator(' '){
+        let repeats: usize = frame.chars().map_while(repeat_power).map(|power|1 << power).sum();
+        if let Some(previous) = frames.last().map(|previous|previous.trim_start_matches([BAR_MARKER, BEAT_MARKER]).to_string()){
+            frames.extend(std::iter::repeat_n(previous, repeats));
+        }
+        // Tokens after the last frame end the piece
+        let frame = frame.trim_start_matches(|char|repeat_power(char).is_some());
+        if !frame.is_empty() || repeats == 0{
+            frames.push(frame.to_string());
+        }
+    }
+    frames
+}
+
+/// Replaces runs of identical frames with repeat tokens, as the compressor writes them
+fn encode_repeats(song: &str)->String{
+    let mut out = String::new();
+    let mut previous = None;
+    let mut repeats = 0usize;
+    // Silent frames, bare spaces in files with a header, repeat like any other
+    for frame in song.split_terminator(' '){
+        if previous == Some(frame){
+            repeats += 1;
+            continue;
+        }
+        out += &repeat_tokens(repeats);
+        repeats = 0;
+        out += frame;
+        out.push(' ');
+        previous = Some(frame.trim_start_matches([BAR_MARKER, BEAT_MARKER]));
+    }
+    out += &repeat_tokens(repeats);
+    out
+}
+
+
+#[derive(Serialize, Deserialize)]
+pub struct Network{
+    #[serde(default)] // Checkpoints from before embeddings have no rows, build_tokenizer turns them away
+    embedding: Embedding,
+    layers: Box<[Layer]>,
+    #[serde(default)] // Checkpoints from before tokenizers were pluggable read characters
+    tokenizer: TokenizerKind,
+    #[serde(default)] // Words of tokenizers with a vocabulary file, so generating doesn't need the file
+    vocabulary: Vec<String>
+}
+impl Network{
+    const INITIAL_WEIGHT_MAX: f32 = 1.0;
+
+
+    fn new_random(rng: &mut ThreadRng, layer_sizes: &[usize], embedding_size: usize, tokenizer: TokenizerKind, vocabulary: Vec<String>)->Self{
+        let a = layer_sizes.iter();
+        let mut b = layer_sizes.iter();
+        b.next();
+
+        Self{
+            embedding: Embedding::new_random(rng, layer_sizes.last().copied().unwrap_or(0), embedding_size),
+            layers: a.zip(b)
+                .map(|(first, second)|Layer::new_random(rng, *first, *second))
+                .collect(),
+            tokenizer,
+            vocabulary
+        }
+    }
+
+    pub fn tokenizer(&self)->TokenizerKind{
+        self.tokenizer
+    }
+
+    /// The tokenizer the network was trained with
+    pub fn build_tokenizer(&self)->std::io::Result<Box<dyn Tokenizer>>{
+        let tokenizer = self.tokenizer.build(&self.vocabulary)?;
+        // Checkpoints from before the special tokens have four outputs less
+        if tokenizer.vocabulary_size() != self.vocabulary_size(){
+            let message = format!("The network has {} outputs but its {} tokenizer has {} tokens, the checkpoint is from an older version",
+                self.vocabulary_size(), self.tokenizer.name(), tokenizer.vocabulary_size());
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message));
+        }
+        if self.embedding.len() != self.vocabulary_size(){
+            let message = "The network has no embedding for its tokens, the checkpoint is from an older version";
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message));
+        }
+        Ok(tokenizer)
+    }
+
+    /// Size of the output layer, one slot per token
+    pub fn vocabulary_size(&self)->usize{
+        self.layers.last().map_or(0, |layer|layer.nodes.len())
+    }
+
+    /// Output and next hidden state for `token` read in the given hidden state
+    pub fn forward(&self, token: usize, hidden_state: &Vector)->(Vector,Vector){
+        self.forward_input(self.input(token, hidden_state))
+    }
+
+    /// What the first layer reads: the embedding of `token`, then the hidden state
+    fn input(&self, token: usize, hidden_state: &Vector)->Vector{
+        Vector::concatenate(self.embedding.row(token), hidden_state)
+    }
+
+    fn forward_input(&self, input: Vector)->(Vector,Vector){
+        let output = self.layers.iter().fold(input, |data_vec, layer|layer.forward(&data_vec));
+        // The last layer's activation is read again as the next hidden state
+        (output.clone(), output)
+    }
+}
+
+/// A learned vector per token, looked up instead of multiplying a one hot input
+#[derive(Serialize, Deserialize, Default)]
+struct Embedding{
+    rows: Box<[Vector]>
+}
+impl Embedding{
+    fn new_random(rng: &mut ThreadRng, vocabulary_size: usize, size: usize)->Self{
+        Self{
+            rows: (0..vocabulary_size).map(|_|Vector::new_random(rng, size)).collect()
+        }
+    }
+
+    /// Number of tokens
+    fn len(&self)->usize{
+        self.rows.len()
+    }
+    /// Floats per token
+    fn size(&self)->usize{
+        self.rows.first().map_or(0, |row|row.0.len())
+    }
+
+    fn row(&self, token: usize)->&Vector{
+        &self.rows[token]
+    }
+
+    /// Moves the row of `token` against its gradient
+    fn update(&mut self, token: usize, gradient: &[f32], learning_rate: f32){
+        for (value, gradient) in self.rows[token].0.iter_mut().zip(gradient){
+            *value -= learning_rate * gradient;
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Layer{
+    nodes: Box<[Node]>
+}
+impl Layer{
+    fn new_random(rng: &mut ThreadRng, previous_layer_size: usize, layer_size: usize)->Self{
+        Self{
+            nodes: (0..layer_size).map(|_|Node::new_random(rng, previous_layer_size)).collect()
+        }
+    }
+
+    /// Output vec size = number of nodes
+    fn forward(&self, input: &Vector)->Vector{
+        self.nodes
+            .iter()
+            .map(|node|
+                node.forward(input)
+            )
+            .collect::<Box<[f32]>>()
+            .into()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Node{
+    input_bias: f32,
+    input_weights: Vector
+}
+impl Node{
+    fn new_random(rng: &mut ThreadRng, previous_layer_size: usize)->Self{
+        Self{
+            input_bias: rng.random_range(-Network::INITIAL_WEIGHT_MAX..Network::INITIAL_WEIGHT_MAX),
+            input_weights: Vector::new_random(rng, previous_layer_size)
+        }
+        
+    }
+
+    fn forward(&self, input: &Vector)->f32{
+        Self::activation(Vector::dot(
+            &self.input_weights,
+            input
+        ) + self.input_bias)
+    }
+
+    fn activation(x: f32)->f32{
+        1.0 / (1.0 + E.powf(-x))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Vector(Box<[f32]>);
+impl Vector{
+    fn new(inner: Box<[f32]>)->Self{
+        Self(inner)
+    }
+
+    pub fn zeros(size: usize)->Self{
+        Self::new((0..size).map(|_|0.0).collect())
+    }
+
+    pub fn get(&self, index: usize)->Option<&f32>{
+        self.0.get(index)
+    }
+
+    fn new_random(rng: &mut ThreadRng, size: usize)->Self{
+        (0..size)
+            .map(|_|rng.random_range(-Network::INITIAL_WEIGHT_MAX..Network::INITIAL_WEIGHT_MAX))
+            .collect::<Box<[f32]>>()
+            .into()
+    }
+
+    /// If the vectors are of different size, "0"s are added to the end of the smaller one, then the dot product is taken
+    fn dot(a: &Vector, b: &Vector)->f32{
+        a.0.iter().zip(b.0.iter()).fold(0.0, |sum,(a,b)|sum+(a*b))
+    }
+
+    pub fn concatenate(a: &Vector, b: &Vector)->Vector{
+        Self::new(a.0.iter().chain(b.0.iter()).copied().collect())
+    }
+}
+impl From<Box<[f32]>> for Vector{
+    fn from(value: Box<[f32]>) -> Self {
+        Self(value)
+    }
+}
+impl Display for Vector{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[")?;
+        for i in self.0.iter() {
+            write!(f, "{}, ", i)?;
+        }
+        write!(f, "]")?;
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn predicted(net: &Network, token: usize)->usize{
+        let (output, _) = net.forward(token, &Vector::zeros(net.vocabulary_size()));
+        (0..output.0.len()).max_by(|a, b|output.0[*a].total_cmp(&output.0[*b])).unwrap()
+    }
+
+    #[test]
+    fn every_token_is_trained_to_predict_the_next(){
+        let config = TrainerConfig{embedding_size: 8, hidden_layers: vec![16], ..TrainerConfig::default()};
+        let mut net = create_network(TokenizerKind::Characters, Vec::new(), &config).unwrap();
+        let tokenizer = net.build_tokenizer().unwrap();
+        let batch = tokenizer.encode_body(&"<H".repeat(10));
+        let (low, high) = (batch[0], batch[1]);
+
+        let untrained = calculate_loss_of_batch(&net, &batch);
+        for _ in 0..300{
+            train_from_loss(&mut net, &batch, 1.0, config.truncate_steps);
+        }
+        assert!(calculate_loss_of_batch(&net, &batch) < untrained / 10.0);
+        assert_eq!(predicted(&net, low), high);
+        assert_eq!(predicted(&net, high), low);
+    }
+
+    #[test]
+    fn the_loss_of_a_batch_leaves_out_its_last_token(){
+        let config = TrainerConfig{embedding_size: 8, hidden_layers: vec![16], ..TrainerConfig::default()};
+        let net = create_network(TokenizerKind::Characters, Vec::new(), &config).unwrap();
+        let (output, _) = net.forward(3, &Vector::zeros(net.vocabulary_size()));
+        assert_eq!(calculate_loss_of_batch(&net, &[3, 5]), calculate_loss_of_one_iteration(&output, 5));
+        assert_eq!(calculate_loss_of_batch(&net, &[3]), 0.0);
+    }
+
+    #[test]
+    fn the_output_is_the_next_hidden_state(){
+        let config = TrainerConfig{embedding_size: 8, hidden_layers: vec![16], ..TrainerConfig::default()};
+        let net = create_network(TokenizerKind::Characters, Vec::new(), &config).unwrap();
+        let (output, hidden_state) = net.forward(3, &Vector::zeros(net.vocabulary_size()));
+        assert_eq!(hidden_state.0.len(), net.vocabulary_size());
+        assert_eq!(hidden_state.0, output.0);
+        // Read back in, it changes what comes out
+        let (next, _) = net.forward(3, &hidden_state);
+        assert_ne!(next.0, output.0);
+    }
+}
@@ -0,0 +1,323 @@
+/*
+    Tokenizers turn the text of a compressed song into the indices the network is trained on, and back
+
+    Characters: one token per .cary character
+    Events: one token per word of the compressor's events encoding, e.g. `t4 v31 on60 off60 bar`
+    Remi: one token per word of the REMI encoding
+    Bpe: merged runs of .cary characters, learned from the corpus, see bpe.rs
+    Chords: a token per frame for the corpus' common chords, characters for the rest, see chords.rs
+
+    Every vocabulary starts with the same four special tokens, and the network works on token indices.
+*/
+
+use std::{collections::HashMap, fs, io, path::Path};
+use serde::{Deserialize, Serialize};
+use smai_config::format::{event_words, remi_words, BAR_MARKER, BEAT_MARKER, CARY_FORMAT_VERSION, REPEAT_TOKEN, REPEAT_TOKENS};
+use crate::{bpe::BpeTokenizer, chords::ChordTokenizer, strip_cary_header};
+
+/// Characters of .cary text: space, the meter markers, the printable pitch characters and the repeat tokens
+pub const CARY_ALPHABET_SIZE: usize = 3 + 94 + REPEAT_TOKENS as usize;
+
+// Every vocabulary starts with these
+pub const PAD: usize = 0; // Fills up a song's last window
+pub const BOS: usize = 1; // Starts every song
+pub const EOS: usize = 2; // Ends every song
+pub const UNK: usize = 3; // Stands for text the vocabulary has no token for
+pub const SPECIAL_TOKENS: [&str; 4] = ["<pad>", "<bos>", "<eos>", "<unk>"];
+
+pub trait Tokenizer{
+    /// Every token the network knows, the special ones first
+    fn vocabulary(&self)->&Vocabulary;
+    /// Tokens of the text after a song's header, UNK for text without a token
+    fn encode_body(&self, body: &str)->Vec<usize>;
+    /// Text of a token sequence, special tokens and indices outside the vocabulary write nothing
+    fn decode(&self, tokens: &[usize])->String{
+        tokens.iter().filter_map(|token|self.vocabulary().text(*token)).collect()
+    }
+    /// Header generated text needs to be read back right, None for plain .cary text
+    fn header(&self)->Option<String>{
+        None
+    }
+
+    /// Number of distinct tokens, the size of the network's input and output vectors
+    fn vocabulary_size(&self)->usize{
+        self.vocabulary().len()
+    }
+    /// Tokens of a song between BOS and EOS, leaving out its header
+    fn encode(&self, song: &str)->Vec<usize>{
+        let mut tokens = vec![BOS];
+        tokens.extend(self.encode_body(strip_cary_header(song)));
+        tokens.push(EOS);
+        tokens
+    }
+}
+
+/// Text of every token in index order, special tokens included
+#[derive(Clone, Default)]
+pub struct Vocabulary{
+    tokens: Vec<String>,
+    indices: HashMap<String, usize>
+}
+impl Vocabulary{
+    /// The special tokens followed by `tokens`
+    pub fn new(tokens: impl IntoIterator<Item=String>)->Self{
+        let mut vocabulary = Self::default();
+        for token in SPECIAL_TOKENS.into_iter().map(str::to_string).chain(tokens){
+            vocabulary.push(token);
+        }
+        vocabulary
+    }
+    /// Adds a token, text already in the vocabulary keeps its first index
+    pub fn push(&mut self, token: String)->usize{
+        let index = self.tokens.len();
+        self.indices.entry(token.clone()).or_insert(index);
+        self.tokens.push(token);
+        index
+    }
+    pub fn len(&self)->usize{
+        self.tokens.len()
+    }
+    pub fn is_empty(&self)->bool{
+        self.tokens.is_empty()
+    }
+    pub fn index(&self, text: &str)->Option<usize>{
+        self.indices.get(text).copied()
+    }
+    /// Text a token decodes to, None for special tokens and indices past the end
+    pub fn text(&self, token: usize)->Option<&str>{
+        self.tokens.get(token).filter(|_|token >= SPECIAL_TOKENS.len()).map(String::as_str)
+    }
+    pub fn tokens(&self)->&[String]{
+        &self.tokens
+    }
+}
+
+/// Which tokenizer a network was trained with, saved alongside its weights
+#[derive(Clone, Copy, PartialEq, Default, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenizerKind{
+    #[default]
+    Characters,
+    Events,
+    Remi,
+    Bpe,
+    Chords
+}
+impl TokenizerKind{
+    /// `vocabulary` is only read by tokenizers with a vocabulary file
+    pub fn build(self, vocabulary: &[String])->io::Result<Box<dyn Tokenizer>>{
+        Ok(match self{
+            Self::Characters => Box::new(CharTokenizer::new()),
+            Self::Events => Box::new(WordTokenizer::events()),
+            Self::Remi => Box::new(WordTokenizer::new(&remi_words(), "remi")),
+            Self::Bpe => Box::new(BpeTokenizer::from_merges(vocabulary).map_err(|e|io::Error::new(io::ErrorKind::InvalidData, e))?),
+            Self::Chords => Box::new(ChordTokenizer::from_chords(vocabulary))
+        })
+    }
+    pub fn name(self)->&'static str{
+        match self{
+            Self::Characters => "characters",
+            Self::Events => "events",
+            Self::Remi => "remi",
+            Self::Bpe => "bpe",
+            Self::Chords => "chords"
+        }
+    }
+    /// Name of the file listing the vocabulary, learned from the songs and saved next to them
+    pub fn vocabulary_file(self)->Option<&'static str>{
+        match self{
+            Self::Bpe => Some("bpe.vocab"),
+            Self::Chords => Some("chords.vocab"),
+            _ => None
+        }
+    }
+    /// Whether files with this %encoding header, None for plain .cary, can be tokenized
+    pub fn reads(self, encoding: Option<&str>)->bool{
+        match self{
+            Self::Characters | Self::Bpe | Self::Chords => !matches!(encoding, Some("events" | "remi")),
+            Self::Events | Self::Remi => encoding == Some(self.name())
+        }
+    }
+}
+
+/// A token per .cary character, the frame separating space and meter markers included
+pub struct CharTokenizer{
+    vocabulary: Vocabulary
+}
+impl CharTokenizer{
+    pub fn new()->Self{
+        Self{vocabulary: Vocabulary::new(cary_alphabet().map(String::from))}
+    }
+}
+impl Default for CharTokenizer{
+    fn default()->Self{
+        Self::new()
+    }
+}
+impl Tokenizer for CharTokenizer{
+    fn vocabulary(&self)->&Vocabulary{
+        &self.vocabulary
+    }
+    fn encode_body(&self, body: &str)->Vec<usize>{
+        let mut buffer = [0; 4];
+        body.chars().map(|char|self.vocabulary.index(char.encode_utf8(&mut buffer)).unwrap_or(UNK)).collect()
+    }
+}
+
+/// A token per whitespace separated word, for the events and REMI encodings
+pub struct WordTokenizer{
+    vocabulary: Vocabulary,
+    encoding: &'static str
+}
+impl WordTokenizer{
+    pub fn new(words: &[String], encoding: &'static str)->Self{
+        Self{vocabulary: Vocabulary::new(words.iter().cloned()), encoding}
+    }
+
+    /// Every word the compressor's events encoding writes
+    pub fn events()->Self{
+        Self::new(&event_words(), "events")
+    }
+}
+impl Tokenizer for WordTokenizer{
+    fn vocabulary(&self)->&Vocabulary{
+        &self.vocabulary
+    }
+    fn encode_body(&self, body: &str)->Vec<usize>{
+        body.split_whitespace().map(|word|self.vocabulary.index(word).unwrap_or(UNK)).collect()
+    }
+    fn decode(&self, tokens: &[usize])->String{
+        join_words(tokens.iter().filter_map(|token|self.vocabulary.text(*token)))
+    }
+    fn header(&self)->Option<String>{
+        Some(format!("%cary {}\n%encoding {}\n%end\n", CARY_FORMAT_VERSION, self.encoding))
+    }
+}
+
+/// The CARY_ALPHABET_SIZE characters tokenizers that build on single characters start from
+pub(crate) fn cary_alphabet()->impl Iterator<Item=char>{
+    [' ', BAR_MARKER, BEAT_MARKER].into_iter()
+        .chain('!'..='~')
+        .chain((0..REPEAT_TOKENS).filter_map(|power|char::from_u32(REPEAT_TOKEN as u32 + power)))
+}
+
+/// Reads a vocabulary file, one word per line
+pub fn load_vocabulary(path: &Path)->io::Result<Vec<String>>{
+    let text = fs::read_to_string(path)
+        .map_err(|e|io::Error::new(e.kind(), format!("Can't read the vocabulary {}: {}", path.display(), e)))?;
+    Ok(text.lines().map(str::trim).filter(|word|!word.is_empty()).map(str::to_string).collect())
+}
+
+/// Space separated words, a line per bar as the compressor writes them
+fn join_words<'a>(words: impl Iterator<Item=&'a str>)->String{
+    let mut out = String::new();
+    for word in words{
+        if !out.is_empty(){
+            out.push(if word == "bar" {'\n'} else {' '});
+        }
+        out += word;
+    }
+    out.push('\n');
+    out
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const SONG: &str = "%cary 1\n%source test.csv\n%end\n8 8< 8< \nDKP DKP \u{c1}D \tD ?C ?C ";
+
+    fn songs()->Vec<String>{
+        vec![SONG.to_string(); 3]
+    }
+
+    /// Text after the header comes back from its tokens, BOS and EOS writing nothing
+    fn assert_round_trip(tokenizer: &dyn Tokenizer, song: &str){
+        let tokens = tokenizer.encode(song);
+        assert_eq!((tokens[0], tokens[tokens.len() - 1]), (BOS, EOS));
+        assert!(!tokens.contains(&UNK), "{:?}", tokens);
+        assert_eq!(tokenizer.decode(&tokens), strip_cary_header(song));
+    }
+
+    #[test]
+    fn characters_round_trip(){
+        assert_round_trip(&CharTokenizer::new(), SONG);
+        assert_eq!(CharTokenizer::new().vocabulary_size(), SPECIAL_TOKENS.len() + CARY_ALPHABET_SIZE);
+    }
+
+    #[test]
+    fn events_round_trip(){
+        assert_round_trip(&WordTokenizer::events(), "%cary 1\n%encoding events\n%end\nv31 on60 t4 off60 on64\nbar t1 off64 beat\n");
+    }
+
+    #[test]
+    fn remi_round_trip(){
+        let tokenizer = TokenizerKind::Remi.build(&[]).unwrap();
+        assert_round_trip(tokenizer.as_ref(), "%cary 1\n%encoding remi\n%end\nmeter16 pos0 tempo120 v31 pitch60 dur4\nbar pos8 pitch64 dur2\n");
+    }
+
+    #[test]
+    fn bpe_round_trip(){
+        let bpe = BpeTokenizer::learn(&songs(), SPECIAL_TOKENS.len() + CARY_ALPHABET_SIZE + 4);
+        assert!(bpe.vocabulary_size() > SPECIAL_TOKENS.len() + CARY_ALPHABET_SIZE);
+        assert!(bpe.encode(SONG).len() < CharTokenizer::new().encode(SONG).len());
+        assert_round_trip(&bpe, SONG);
+    }
+
+    #[test]
+    fn chords_round_trip(){
+        let chords = ChordTokenizer::learn(&songs(), 2, 16);
+        assert!(!chords.chord_lines().is_empty());
+        assert_round_trip(&chords, SONG);
+    }
+
+    #[test]
+    fn special_tokens_keep_their_indices(){
+        let songs = songs();
+        let tokenizers: [Box<dyn Tokenizer>; 5] = [
+            Box::new(CharTokenizer::new()),
+            Box::new(WordTokenizer::events()),
+            TokenizerKind::Remi.build(&[]).unwrap(),
+            Box::new(BpeTokenizer::learn(&songs, 200)),
+            Box::new(ChordTokenizer::learn(&songs, 2, 16))
+        ];
+        for tokenizer in tokenizers{
+            let tokens = &tokenizer.vocabulary().tokens()[..SPECIAL_TOKENS.len()];
+            assert_eq!(tokens, SPECIAL_TOKENS);
+            assert_eq!([PAD, BOS, EOS, UNK].map(|token|tokenizer.vocabulary().index(SPECIAL_TOKENS[token])), [PAD, BOS, EOS, UNK].map(Some));
+            assert_eq!(tokenizer.decode(&[PAD, BOS, EOS, UNK]), if tokenizer.header().is_some() {"\n"} else {""});
+        }
+    }
+
+    #[test]
+    fn unseen_symbols_are_unknown(){
+        let characters = CharTokenizer::new();
+        let index = |text|characters.vocabulary().index(text).unwrap();
+        assert_eq!(characters.encode_body("8é "), [index("8"), UNK, index(" ")]);
+
+        let events = WordTokenizer::events();
+        assert_eq!(events.encode_body("on60 on128 t0 pitch60"), [events.vocabulary().index("on60").unwrap(), UNK, UNK, UNK]);
+    }
+
+    #[test]
+    fn vocabularies_reload_from_their_saved_lines(){
+        let songs = songs();
+        let bpe = BpeTokenizer::learn(&songs, 200);
+        let chords = ChordTokenizer::learn(&songs, 2, 16);
+        let saved: [(TokenizerKind, Vec<String>, &dyn Tokenizer); 2] = [
+            (TokenizerKind::Bpe, bpe.merge_lines(), &bpe),
+            (TokenizerKind::Chords, chords.chord_lines(), &chords)
+        ];
+        for (kind, lines, learned) in saved{
+            let reloaded = kind.build(&lines).unwrap();
+            assert_eq!(reloaded.vocabulary().tokens(), learned.vocabulary().tokens(), "{}", kind.name());
+            assert_eq!(reloaded.encode(SONG), learned.encode(SONG), "{}", kind.name());
+        }
+
+        let path = std::env::temp_dir().join(format!("smai_tokenizer_test_{}.vocab", std::process::id()));
+        bpe.save(&path).unwrap();
+        let loaded = load_vocabulary(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), bpe.merge_lines());
+    }
+}
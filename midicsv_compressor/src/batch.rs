@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use smai_config::hash::{fnv1a, FNV_OFFSET};
+use crate::manifest::{Manifest, MANIFEST_FILE};
+use crate::{CompressError, CompressorConfig, FileReport, MidiProcessor, OutputTarget};
+
+// Constants
+const INDEX_FILE: &str = ".compressed_index";  // Hash and name of every file compressed into a directory
+
+/// How a directory run should behave
+pub struct BatchOptions {
+    pub jobs: usize,
+    pub force: bool,  // Compress again even when the index says a file is up to date
+}
+
+enum Outcome {
+    Compressed { report: FileReport, content_hash: u64, index_hash: u64 },
+    Unchanged,
+    Failed(CompressError),
+}
+
+/// Compresses every file of `input_dir` on a pool of worker threads, each with its own processor.
+/// Files whose content and settings hash match the output directory's index are skipped,
+/// so an interrupted run picks up where it stopped. Every run rewrites the directory's manifest.
+pub fn compress_directory(input_dir: &Path, output_dir: &Path, config: &CompressorConfig, options: &BatchOptions) -> io::Result<()> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(input_dir)? {
+        let path = entry?.path();
+        if path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+
+    let index_path = output_dir.join(INDEX_FILE);
+    let index = if options.force { HashMap::new() } else { read_index(&index_path)? };
+    let mut index_file = OpenOptions::new().create(true).append(true).open(&index_path)?;
+    let manifest_path = output_dir.join(MANIFEST_FILE);
+    let previous_manifest = Manifest::read(&manifest_path)?;
+    let mut manifest = Manifest::default();
+    let settings_hash = fnv1a(FNV_OFFSET, format!("{:?}", config).as_bytes());
+    let output = OutputTarget::Directory(output_dir.to_path_buf());
+
+    let next_file = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+    let mut compressed = 0;
+    let mut unchanged = 0;
+    let mut failures = Vec::new();
+
+    thread::scope(|scope| -> io::Result<()> {
+        for _ in 0..options.jobs.clamp(1, files.len().max(1)) {
+            let sender = sender.clone();
+            let (files, index, previous_manifest, output, next_file) = (&files, &index, &previous_manifest, &output, &next_file);
+            scope.spawn(move || {
+                let mut processor = MidiProcessor::new(config);
+                while let Some(path) = files.get(next_file.fetch_add(1, Ordering::Relaxed)) {
+                    let outcome = compress_if_changed(&mut processor, path, output, index, previous_manifest, settings_hash, config);
+                    if sender.send((path, outcome)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        // Results arrive in completion order, the index is appended to as soon as a file is done
+        for (done, (path, outcome)) in receiver.iter().enumerate() {
+            let filename = file_name(path);
+            let status = match outcome {
+                Outcome::Compressed { report, content_hash, index_hash } => {
+                    report.print();
+                    writeln!(index_file, "{:016x} {}", index_hash, filename)?;
+                    manifest.compressed(&filename, content_hash, &report.stats, report.skipped.len());
+                    compressed += 1;
+                    "compressed"
+                }
+                Outcome::Unchanged => {
+                    manifest.unchanged(&filename, &previous_manifest);
+                    unchanged += 1;
+                    "unchanged, skipped"
+                }
+                Outcome::Failed(error) => {
+                    manifest.failed(&filename);
+                    failures.push(error.to_string());
+                    "failed"
+                }
+            };
+            eprintln!("[{}/{}] {} {}", done + 1, files.len(), filename, status);
+        }
+        Ok(())
+    })?;
+    manifest.write(&manifest_path)?;
+
+    eprintln!("Compressed {}, skipped {} unchanged, {} failed", compressed, unchanged, failures.len());
+    if failures.is_empty() {
+        return Ok(());
+    }
+    for failure in &failures {
+        eprintln!("  {}", failure);
+    }
+    Err(io::Error::other(format!("{} of {} files failed", failures.len(), files.len())))
+}
+
+fn compress_if_changed(
+    processor: &mut MidiProcessor,
+    path: &Path,
+    output: &OutputTarget,
+    index: &HashMap<String, u64>,
+    previous_manifest: &Manifest,
+    settings_hash: u64,
+    config: &CompressorConfig,
+) -> Outcome {
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(source) => return Outcome::Failed(CompressError::Io { path: path.to_path_buf(), source }),
+    };
+    let content_hash = fnv1a(FNV_OFFSET, &content);
+    let index_hash = fnv1a(settings_hash, &content);
+
+    // Outputs deleted since the last run are written again, as are files the manifest has no stats for
+    let filename = file_name(path);
+    let first_output = output.path(&MidiProcessor::output_name(&filename, (!config.normalize_key).then_some(config.transpositions.0), config.tempo_stretches[0]));
+    if index.get(&filename) == Some(&index_hash) && first_output.exists() && previous_manifest.has_stats(&filename) {
+        return Outcome::Unchanged;
+    }
+
+    match processor.process_file(path, output) {
+        Ok(report) => Outcome::Compressed { report, content_hash, index_hash },
+        Err(error) => Outcome::Failed(error),
+    }
+}
+
+/// Reads the index, later lines replacing earlier ones for the same file
+fn read_index(path: &Path) -> io::Result<HashMap<String, u64>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e),
+    };
+    Ok(text
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .filter_map(|(hash, name)| Some((name.to_string(), u64::from_str_radix(hash, 16).ok()?)))
+        .collect())
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map_or_else(|| path.to_string_lossy(), |name| name.to_string_lossy()).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SONG: &str = "0, 0, Header, 1, 2, 384\n2, 0, Note_on_c, 0, 60, 100\n2, 384, Note_off_c, 0, 60, 0\n";
+
+    fn manifest_rows(output_dir: &Path) -> Vec<String> {
+        fs::read_to_string(output_dir.join(MANIFEST_FILE)).unwrap().lines().skip(1).map(str::to_string).collect()
+    }
+
+    #[test]
+    fn a_resumed_run_records_the_stats_of_every_file() {
+        let directory = std::env::temp_dir().join(format!("batch_resume_test_{}", std::process::id()));
+        let (input_dir, output_dir) = (directory.join("in"), directory.join("out"));
+        fs::create_dir_all(&input_dir).unwrap();
+        fs::create_dir_all(&output_dir).unwrap();
+        fs::write(input_dir.join("a, b.csv"), SONG).unwrap();
+        fs::write(input_dir.join("c.csv"), SONG).unwrap();
+
+        let config = CompressorConfig::default();
+        let options = BatchOptions { jobs: 2, force: false };
+        compress_directory(&input_dir, &output_dir, &config, &options).unwrap();
+        let compressed = manifest_rows(&output_dir);
+        assert!(compressed.iter().all(|row| row.contains(",compressed,9,1,60,60,")), "{:?}", compressed);
+
+        // Unchanged files keep the stats of the run that compressed them
+        compress_directory(&input_dir, &output_dir, &config, &options).unwrap();
+        assert_eq!(manifest_rows(&output_dir), compressed);
+
+        // A run interrupted before writing its manifest left the files in the index only
+        fs::remove_file(output_dir.join(MANIFEST_FILE)).unwrap();
+        compress_directory(&input_dir, &output_dir, &config, &options).unwrap();
+        let resumed = manifest_rows(&output_dir);
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(resumed, compressed);
+    }
+}
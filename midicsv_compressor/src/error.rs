@@ -0,0 +1,173 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use serde::Deserialize;
+use crate::PieceStats;
+
+// Constants
+const ERROR_POLICY: ErrorPolicy = ErrorPolicy::Skip;
+const MAX_REPORTED_LINES: usize = 10;  // Per file, the rest are only counted
+
+/// What is wrong with a midicsv line, each kind has its own policy
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LineErrorKind {
+    /// The record has fewer fields than its type needs
+    MissingFields,
+    /// A numeric field doesn't parse
+    MalformedNumber,
+    /// A channel, pitch, velocity or meter outside what MIDI allows
+    OutOfRange,
+    /// Quoted text is unterminated or badly escaped
+    InvalidText,
+    /// The record type is not one midicsv writes
+    UnknownRecord,
+}
+
+impl LineErrorKind {
+    fn name(&self) -> &'static str {
+        match self {
+            LineErrorKind::MissingFields => "missing fields",
+            LineErrorKind::MalformedNumber => "malformed number",
+            LineErrorKind::OutOfRange => "out of range",
+            LineErrorKind::InvalidText => "invalid text",
+            LineErrorKind::UnknownRecord => "unknown record",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LineError {
+    pub line: usize,  // 1-based, 0 until the line is known
+    pub kind: LineErrorKind,
+    pub message: String,
+}
+
+impl LineError {
+    pub fn new(kind: LineErrorKind, message: String) -> Self {
+        LineError { line: 0, kind, message }
+    }
+
+    pub fn at_line(self, line: usize) -> Self {
+        LineError { line, ..self }
+    }
+
+    pub(crate) fn out_of_range(what: String) -> Self {
+        LineError::new(LineErrorKind::OutOfRange, format!("{} is outside the MIDI range", what))
+    }
+}
+
+/// What to do with a line that has an error
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorPolicy {
+    /// Drop the line, note it in the file's report and carry on
+    Skip,
+    /// Give up on the file, later files are still compressed
+    Fail,
+}
+
+/// Policy for every kind of line error, read from [compressor.errors]
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ErrorPolicies {
+    pub missing_fields: ErrorPolicy,
+    pub malformed_number: ErrorPolicy,
+    pub out_of_range: ErrorPolicy,
+    pub invalid_text: ErrorPolicy,
+    pub unknown_record: ErrorPolicy,
+}
+
+impl ErrorPolicies {
+    pub fn for_kind(&self, kind: LineErrorKind) -> ErrorPolicy {
+        match kind {
+            LineErrorKind::MissingFields => self.missing_fields,
+            LineErrorKind::MalformedNumber => self.malformed_number,
+            LineErrorKind::OutOfRange => self.out_of_range,
+            LineErrorKind::InvalidText => self.invalid_text,
+            LineErrorKind::UnknownRecord => self.unknown_record,
+        }
+    }
+}
+
+impl Default for ErrorPolicies {
+    fn default() -> Self {
+        ErrorPolicies {
+            missing_fields: ERROR_POLICY,
+            malformed_number: ERROR_POLICY,
+            out_of_range: ERROR_POLICY,
+            invalid_text: ERROR_POLICY,
+            unknown_record: ERROR_POLICY,
+        }
+    }
+}
+
+/// Why a file could not be compressed
+#[derive(Debug)]
+pub enum CompressError {
+    Io { path: PathBuf, source: io::Error },
+    Line { file: String, error: LineError },
+}
+
+impl fmt::Display for CompressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompressError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            CompressError::Line { file, error } => write!(f, "{}:{}: {} ({})", file, error.line, error.message, error.kind.name()),
+        }
+    }
+}
+
+impl std::error::Error for CompressError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CompressError::Io { source, .. } => Some(source),
+            CompressError::Line { .. } => None,
+        }
+    }
+}
+
+impl From<CompressError> for io::Error {
+    fn from(error: CompressError) -> Self {
+        let kind = match &error {
+            CompressError::Io { source, .. } => source.kind(),
+            CompressError::Line { .. } => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, error)
+    }
+}
+
+/// Everything that was dropped or looked wrong while compressing one file, and what was written.
+/// Workers only fill it in, so the reports of parallel files print whole, from the main thread.
+pub struct FileReport {
+    pub file: String,
+    pub skipped: Vec<LineError>,  // Lines dropped under the skip policy
+    pub warnings: Vec<String>,    // Valid events the compressor could not keep
+    pub notes: Vec<String>,       // Quantization error, files written and encoding sizes
+    pub stats: PieceStats,
+}
+
+impl FileReport {
+    pub fn new(file: String) -> Self {
+        FileReport { file, skipped: Vec::new(), warnings: Vec::new(), notes: Vec::new(), stats: PieceStats::default() }
+    }
+
+    pub fn print(&self) {
+        for note in &self.notes {
+            eprintln!("{}: {}", self.file, note);
+        }
+        for warning in &self.warnings {
+            eprintln!("{}: warning: {}", self.file, warning);
+        }
+        if self.skipped.is_empty() {
+            return;
+        }
+
+        eprintln!("{}: skipped {} lines", self.file, self.skipped.len());
+        for error in self.skipped.iter().take(MAX_REPORTED_LINES) {
+            eprintln!("  line {}: {} ({})", error.line, error.message, error.kind.name());
+        }
+        if self.skipped.len() > MAX_REPORTED_LINES {
+            eprintln!("  and {} more", self.skipped.len() - MAX_REPORTED_LINES);
+        }
+    }
+}
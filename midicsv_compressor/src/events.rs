@@ -0,0 +1,89 @@
+use std::io::{self, Write};
+use smai_config::format::{bin_of_velocity, velocity_of_bin, BAR_MARKER, EVENT_MARKERS, MAX_TIME_SHIFT};
+use crate::{AugmentedPiece, NoteState, MIDI_PITCHES};
+
+// Constants
+pub(crate) const DEFAULT_VELOCITY: u8 = 127;  // For notes already sounding when a cropped piece starts
+
+/// Writes a piece as `on<pitch>`, `off<pitch>`, `t<frames>` and `v<bin>` tokens, plus `bar` and `beat`
+/// for meter markers. Pitches are MIDI note numbers after transposition. Only the pitches a frame
+/// would hold are written, `is_written` tells which. Returns the bytes written.
+pub(crate) fn write_events(
+    output_file: &mut impl Write,
+    piece: &AugmentedPiece,
+    transposition: i32,
+    is_written: impl Fn(usize) -> bool,
+) -> io::Result<usize> {
+    let mut tokens = Vec::new();
+    let mut sounding = [false; MIDI_PITCHES];
+    let mut velocity_bin = None;
+    let mut last_event = 0;
+    let written: Vec<usize> = (0..MIDI_PITCHES).filter(|pitch| is_written(*pitch)).collect();
+
+    // The extra frame past the end releases every note still sounding
+    let silence = [NoteState::Off; MIDI_PITCHES];
+    let frames = piece.frames.iter().map(Some).chain([None]);
+    for (time, (notes, marker)) in frames.zip(piece.markers.iter().copied().chain([None])).enumerate() {
+        let notes = notes.unwrap_or(&silence);
+        let mut events = Vec::new();
+        if let Some(marker) = marker {
+            events.push(if marker == BAR_MARKER { EVENT_MARKERS[0] } else { EVENT_MARKERS[1] }.to_string());
+        }
+
+        // Releases first, so a note struck again in the same frame reads off then on
+        for &pitch in &written {
+            if sounding[pitch] && matches!(notes[pitch], NoteState::Off | NoteState::On(_)) {
+                events.push(format!("off{}", pitch as i32 + transposition));
+                sounding[pitch] = false;
+            }
+        }
+        for &pitch in &written {
+            let velocity = match notes[pitch] {
+                _ if sounding[pitch] => continue,
+                NoteState::Off => continue,
+                NoteState::On(velocity) => velocity,
+                NoteState::Sustained => velocity_bin.map_or(DEFAULT_VELOCITY, |bin: u8| velocity_of_bin(bin.into())),
+            };
+            let bin = bin_of_velocity(velocity);
+            if velocity_bin != Some(bin) {
+                events.push(format!("v{}", bin));
+                velocity_bin = Some(bin);
+            }
+            events.push(format!("on{}", pitch as i32 + transposition));
+            sounding[pitch] = true;
+        }
+
+        if events.is_empty() {
+            continue;
+        }
+        let mut shifts = Vec::new();
+        let mut shift = time - last_event;
+        while shift > 0 {
+            let frames = shift.min(MAX_TIME_SHIFT);
+            shifts.push(format!("t{}", frames));
+            shift -= frames;
+        }
+        last_event = time;
+
+        tokens.extend(shifts);
+        tokens.extend(events);
+    }
+    write_tokens(output_file, tokens)
+}
+
+/// Writes space separated tokens, starting a new line at every `bar`. Returns the bytes written.
+pub(crate) fn write_tokens(output_file: &mut impl Write, tokens: Vec<String>) -> io::Result<usize> {
+    let mut out = String::new();
+    for token in tokens {
+        // A line per bar keeps the files readable
+        let separator = if token == EVENT_MARKERS[0] { '\n' } else { ' ' };
+        if !out.is_empty() {
+            out.push(separator);
+        }
+        out += &token;
+    }
+
+    out.push('\n');
+    write!(output_file, "{}", out)?;
+    Ok(out.len())
+}
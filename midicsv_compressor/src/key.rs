@@ -0,0 +1,166 @@
+// Constants
+const PITCH_CLASSES: usize = 12;
+// Krumhansl-Kessler probe tone ratings, starting from the tonic
+const MAJOR_PROFILE: [f32; PITCH_CLASSES] = [6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88];
+const MINOR_PROFILE: [f32; PITCH_CLASSES] = [6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17];
+const MAJOR_NAMES: [&str; PITCH_CLASSES] = ["C", "Db", "D", "Eb", "E", "F", "F#", "G", "Ab", "A", "Bb", "B"];
+const MINOR_NAMES: [&str; PITCH_CLASSES] = ["C", "C#", "D", "Eb", "E", "F", "F#", "G", "G#", "A", "Bb", "B"];
+const MINOR_NORMALIZED_TONIC: i32 = 9;  // A minor shares C major's notes
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum KeySource {
+    /// Read from a Key_signature event
+    Signature,
+    /// Estimated from how long each pitch class sounds
+    Estimated,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Key {
+    pub tonic: usize,  // Pitch class, 0 is C
+    pub minor: bool,
+    pub source: KeySource,
+}
+
+impl Key {
+    /// Key of a Key_signature event, `sharps` is negative for flats
+    pub fn from_signature(sharps: i8, minor: bool) -> Self {
+        // Every sharp moves the major tonic a fifth up
+        let major_tonic = (sharps as i32 * 7).rem_euclid(PITCH_CLASSES as i32);
+        let tonic = if minor { (major_tonic + MINOR_NORMALIZED_TONIC) % PITCH_CLASSES as i32 } else { major_tonic };
+        Key { tonic: tonic as usize, minor, source: KeySource::Signature }
+    }
+
+    /// Krumhansl-Schmuckler: the key whose profile correlates best with the pitch class durations,
+    /// None when nothing sounds
+    pub fn estimate(durations: &[f32; PITCH_CLASSES]) -> Option<Self> {
+        if durations.iter().all(|duration| *duration == 0.0) {
+            return None;
+        }
+
+        let mut best = None;
+        let mut best_correlation = f32::NEG_INFINITY;
+        for (minor, profile) in [(false, &MAJOR_PROFILE), (true, &MINOR_PROFILE)] {
+            for tonic in 0..PITCH_CLASSES {
+                let rotated: Vec<f32> = (0..PITCH_CLASSES).map(|class| durations[(tonic + class) % PITCH_CLASSES]).collect();
+                let correlation = correlation(&rotated, profile);
+                if correlation > best_correlation {
+                    best_correlation = correlation;
+                    best = Some(Key { tonic, minor, source: KeySource::Estimated });
+                }
+            }
+        }
+        best
+    }
+
+    /// Semitones that move this key to C major or A minor, between -6 and 5
+    pub fn normalizing_shift(&self) -> i32 {
+        let target = if self.minor { MINOR_NORMALIZED_TONIC } else { 0 };
+        let shift = (target - self.tonic as i32).rem_euclid(PITCH_CLASSES as i32);
+        if shift > 5 { shift - PITCH_CLASSES as i32 } else { shift }
+    }
+
+    /// As written in the .cary header, e.g. `F# minor estimated`
+    pub fn describe(&self) -> String {
+        let (name, mode) = if self.minor { (MINOR_NAMES[self.tonic], "minor") } else { (MAJOR_NAMES[self.tonic], "major") };
+        let source = match self.source {
+            KeySource::Signature => "signature",
+            KeySource::Estimated => "estimated",
+        };
+        format!("{} {} {}", name, mode, source)
+    }
+}
+
+/// Pearson correlation, 0 when either side is constant
+fn correlation(a: &[f32], b: &[f32]) -> f32 {
+    let mean_a = a.iter().sum::<f32>() / a.len() as f32;
+    let mean_b = b.iter().sum::<f32>() / b.len() as f32;
+    let mut covariance = 0.0;
+    let mut variance_a = 0.0;
+    let mut variance_b = 0.0;
+    for (a, b) in a.iter().zip(b) {
+        covariance += (a - mean_a) * (b - mean_b);
+        variance_a += (a - mean_a).powi(2);
+        variance_b += (b - mean_b).powi(2);
+    }
+    if variance_a == 0.0 || variance_b == 0.0 {
+        return 0.0;
+    }
+    covariance / (variance_a * variance_b).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pitch class durations of a scale, with the tonic triad held twice as long
+    fn durations(scale: &[usize], triad: [usize; 3]) -> [f32; PITCH_CLASSES] {
+        let mut durations = [0.0; PITCH_CLASSES];
+        for class in scale {
+            durations[*class] = 1.0;
+        }
+        for class in triad {
+            durations[class] = 2.0;
+        }
+        durations
+    }
+
+    fn estimate(scale: &[usize], triad: [usize; 3]) -> (usize, bool) {
+        let key = Key::estimate(&durations(scale, triad)).unwrap();
+        assert_eq!(key.source, KeySource::Estimated);
+        (key.tonic, key.minor)
+    }
+
+    #[test]
+    fn estimate_finds_major_keys() {
+        assert_eq!(estimate(&[0, 2, 4, 5, 7, 9, 11], [0, 4, 7]), (0, false));
+        assert_eq!(estimate(&[2, 4, 6, 7, 9, 11, 1], [2, 6, 9]), (2, false));
+        assert_eq!(estimate(&[3, 5, 7, 8, 10, 0, 2], [3, 7, 10]), (3, false));
+    }
+
+    #[test]
+    fn estimate_finds_minor_keys() {
+        // Harmonic minor, the raised seventh tells it apart from the relative major
+        assert_eq!(estimate(&[9, 11, 0, 2, 4, 5, 8], [9, 0, 4]), (9, true));
+        assert_eq!(estimate(&[4, 6, 7, 9, 11, 0, 3], [4, 7, 11]), (4, true));
+        assert_eq!(estimate(&[0, 2, 3, 5, 7, 8, 11], [0, 3, 7]), (0, true));
+    }
+
+    #[test]
+    fn estimate_needs_something_to_sound() {
+        assert_eq!(Key::estimate(&[0.0; PITCH_CLASSES]), None);
+    }
+
+    #[test]
+    fn signatures_count_fifths_from_c() {
+        let key = |sharps, minor| {
+            let key = Key::from_signature(sharps, minor);
+            assert_eq!(key.source, KeySource::Signature);
+            key.describe()
+        };
+        assert_eq!(key(0, false), "C major signature");
+        assert_eq!(key(2, false), "D major signature");
+        assert_eq!(key(-3, false), "Eb major signature");
+        assert_eq!(key(6, false), "F# major signature");
+        assert_eq!(key(-7, false), "B major signature");
+        assert_eq!(key(0, true), "A minor signature");
+        assert_eq!(key(1, true), "E minor signature");
+        assert_eq!(key(-1, true), "D minor signature");
+        assert_eq!(key(4, true), "C# minor signature");
+    }
+
+    #[test]
+    fn normalizing_shift_takes_the_shorter_way() {
+        let shift = |tonic, minor| Key { tonic, minor, source: KeySource::Estimated }.normalizing_shift();
+        assert_eq!(shift(0, false), 0);
+        assert_eq!(shift(2, false), -2);
+        assert_eq!(shift(1, false), -1);
+        assert_eq!(shift(5, false), -5);
+        assert_eq!(shift(6, false), -6);
+        assert_eq!(shift(7, false), 5);
+        assert_eq!(shift(9, true), 0);
+        assert_eq!(shift(4, true), 5);
+        assert_eq!(shift(0, true), -3);
+        assert_eq!(shift(11, true), -2);
+    }
+}
@@ -0,0 +1,1203 @@
+use std::cmp::Reverse;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufRead, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::thread;
+use clap::Args;
+use rand::{rngs::ThreadRng, Rng};
+use serde::Deserialize;
+use smai_config::format::{repeat_tokens, BAR_MARKER, BEAT_MARKER, CARY_FORMAT_VERSION, MIDI_PITCHES};
+use smai_config::{ConfigArgs, Section};
+
+mod batch;
+mod error;
+mod events;
+mod key;
+mod manifest;
+pub mod midicsv;
+mod remi;
+pub use batch::{compress_directory, BatchOptions};
+pub use error::{CompressError, ErrorPolicies, ErrorPolicy, FileReport, LineError, LineErrorKind};
+pub use manifest::PieceStats;
+use key::Key;
+use midicsv::{MidiCsvLine, MidiCsvRecord};
+
+// Constants
+pub const INPUT_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../data/input/midicsv/");
+pub const OUTPUT_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../data/input/cary/");
+const MAX_PITCHES: usize = 110;
+const MIN_PITCH: i32 = 22;
+const MAX_TIME_STEPS: usize = 150_000;
+const MIDI_CHANNELS: usize = 16;
+const PEDAL_MODE: PedalMode = PedalMode::Sustain;
+const MAX_POLYPHONY: Option<usize> = None;
+const POLYPHONY_POLICY: PolyphonyPolicy = PolyphonyPolicy::KeepHighest;
+const SUSTAIN_CONTROLLER: u8 = 64;
+const SOSTENUTO_CONTROLLER: u8 = 66;
+const PEDAL_DOWN_THRESHOLD: u8 = 64;  // Controller values at or above this count as pressed
+const QUANTIZATION: Quantization = Quantization::Legacy;
+const DEFAULT_TICKS_PER_QUARTER: f32 = 384.0;
+const MIXED_STEPS_PER_QUARTER: u32 = 12;  // Smallest grid holding both 1/16 and 1/24 positions
+const EMIT_METER_MARKERS: bool = false;
+const TRANSPOSITIONS: (i32, i32) = (-6, 5);
+const NORMALIZE_KEY: bool = false;
+const TEMPO_STRETCHES: [f32; 1] = [1.0];
+const VELOCITY_JITTER: u8 = 0;
+const CROP_FRAMES: Option<usize> = None;
+const WRITE_HEADER: bool = true;
+const ENCODING: Encoding = Encoding::Plain;
+
+/// The [compressor] section: how midicsv notes are quantized and cut down to the printable pitch range,
+/// which augmented variants are written, and in what encoding
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressorConfig {
+    pub max_pitches: usize,  // Notes at or above this MIDI pitch are dropped
+    pub min_pitch: i32,      // MIDI pitch written as '!'
+    pub max_time_steps: usize,
+    pub pedal_mode: PedalMode,
+    pub max_polyphony: Option<usize>,  // Notes sounding at once, the policy picks which to drop
+    pub polyphony_policy: PolyphonyPolicy,
+    pub quantization: Quantization,
+    pub emit_meter_markers: bool,
+    pub transpositions: (i32, i32),  // Lowest and highest, inclusive
+    pub normalize_key: bool,         // Write each piece once, moved to C major or A minor, instead of every transposition
+    pub tempo_stretches: Vec<f32>,
+    pub velocity_jitter: u8,
+    pub crop_frames: Option<usize>,
+    pub write_header: bool,
+    pub encoding: Encoding,
+    pub errors: ErrorPolicies,
+}
+
+impl Default for CompressorConfig {
+    fn default() -> Self {
+        CompressorConfig {
+            max_pitches: MAX_PITCHES,
+            min_pitch: MIN_PITCH,
+            max_time_steps: MAX_TIME_STEPS,
+            pedal_mode: PEDAL_MODE,
+            max_polyphony: MAX_POLYPHONY,
+            polyphony_policy: POLYPHONY_POLICY,
+            quantization: QUANTIZATION,
+            emit_meter_markers: EMIT_METER_MARKERS,
+            transpositions: TRANSPOSITIONS,
+            normalize_key: NORMALIZE_KEY,
+            tempo_stretches: TEMPO_STRETCHES.to_vec(),
+            velocity_jitter: VELOCITY_JITTER,
+            crop_frames: CROP_FRAMES,
+            write_header: WRITE_HEADER,
+            encoding: ENCODING,
+            errors: ErrorPolicies::default(),
+        }
+    }
+}
+
+impl Section for CompressorConfig {
+    const NAME: &'static str = "compressor";
+
+    fn validate(&self) -> Result<(), String> {
+        if self.max_pitches > MIDI_PITCHES {
+            return Err(format!("max_pitches can be at most {}", MIDI_PITCHES));
+        }
+        if !(0..MIDI_PITCHES as i32).contains(&self.min_pitch) {
+            return Err("min_pitch must be a MIDI pitch".to_string());
+        }
+        if self.max_time_steps == 0 {
+            return Err("max_time_steps must be positive".to_string());
+        }
+        if self.max_polyphony == Some(0) {
+            return Err("max_polyphony must be positive".to_string());
+        }
+        if self.transpositions.0 > self.transpositions.1 {
+            return Err("transpositions must list the lowest first".to_string());
+        }
+        if self.tempo_stretches.is_empty() || self.tempo_stretches.iter().any(|stretch| *stretch <= 0.0) {
+            return Err("tempo_stretches must hold at least one positive factor".to_string());
+        }
+        if self.velocity_jitter > 126 {
+            return Err("velocity_jitter can be at most 126".to_string());
+        }
+        if self.crop_frames == Some(0) {
+            return Err("crop_frames must be positive".to_string());
+        }
+        if matches!(self.encoding, Encoding::Events | Encoding::Remi) && !self.write_header {
+            return Err(format!("the {} encoding needs write_header, the decompressor tells formats apart by it", self.encoding.name()));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum NoteState {
+    Off,
+    On(u8),  // Onset, with its velocity
+    Sustained,
+}
+
+/// Variations written for every input file to enlarge the training set
+struct Augmentation {
+    transpositions: RangeInclusive<i32>,
+    tempo_stretches: Vec<f32>,  // Frame count multipliers, 2.0 plays twice as slow
+    velocity_jitter: u8,        // The .cary format has no velocities, only formats carrying them are affected
+    crop_frames: Option<usize>, // Keep a random excerpt of this many frames instead of the whole piece
+}
+
+impl Augmentation {
+    fn new(config: &CompressorConfig) -> Self {
+        Augmentation {
+            transpositions: config.transpositions.0..=config.transpositions.1,
+            tempo_stretches: config.tempo_stretches.clone(),
+            velocity_jitter: config.velocity_jitter,
+            crop_frames: config.crop_frames,
+        }
+    }
+
+    /// Resamples the frames so the piece lasts `stretch` times as long
+    fn stretch(&self, frames: &[[NoteState; MIDI_PITCHES]], markers: &[Option<char>], tempos: &[Option<u32>], stretch: f32) -> AugmentedPiece {
+        let length = (frames.len() as f32 * stretch).ceil() as usize;
+        let mut piece = AugmentedPiece {
+            frames: Vec::with_capacity(length),
+            markers: Vec::with_capacity(length),
+            tempos: Vec::with_capacity(length),
+            start_frame: 0,
+        };
+
+        let mut previous_source = None;
+        for frame in 0..length {
+            let source = ((frame as f32 / stretch) as usize).min(frames.len() - 1);
+            let repeated = previous_source == Some(source);
+            previous_source = Some(source);
+
+            // A repeated frame continues its notes rather than striking them again
+            piece.frames.push(frames[source].map(|state| match state {
+                NoteState::On(_) if repeated => NoteState::Sustained,
+                state => state,
+            }));
+            piece.markers.push(if repeated { None } else { markers[source] });
+            piece.tempos.push(if repeated { None } else { tempos[source] });
+        }
+        piece
+    }
+
+    fn jitter_velocities(&self, piece: &mut AugmentedPiece, rng: &mut ThreadRng) {
+        if self.velocity_jitter == 0 {
+            return;
+        }
+        let jitter = self.velocity_jitter as i32;
+        for state in piece.frames.iter_mut().flatten() {
+            if let NoteState::On(velocity) = state {
+                *velocity = (*velocity as i32 + rng.random_range(-jitter..=jitter)).clamp(1, 127) as u8;
+            }
+        }
+    }
+
+    fn crop(&self, piece: &mut AugmentedPiece, rng: &mut ThreadRng) {
+        let Some(crop_frames) = self.crop_frames else {
+            return;
+        };
+        if crop_frames < piece.frames.len() {
+            let start = rng.random_range(0..=piece.frames.len() - crop_frames);
+            piece.frames.drain(..start);
+            piece.frames.truncate(crop_frames);
+            piece.markers.drain(..start);
+            piece.markers.truncate(crop_frames);
+            piece.tempos.drain(..start);
+            piece.tempos.truncate(crop_frames);
+            piece.start_frame = start;
+        }
+    }
+}
+
+/// One augmented copy of a piece, ready to be transposed and written out
+struct AugmentedPiece {
+    frames: Vec<[NoteState; MIDI_PITCHES]>,
+    markers: Vec<Option<char>>,
+    tempos: Vec<Option<u32>>,  // Microseconds per quarter note, at the frames the tempo changes
+    start_frame: usize,  // Stretched frames cropped off the start
+}
+
+/// How sustain (CC64) and sostenuto (CC66) pedal events affect note durations
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PedalMode {
+    /// Keep the durations written in the file and ignore pedal events
+    Raw,
+    /// Keep released notes sounding while the sustain pedal is down
+    Sustain,
+    /// Like `Sustain`, and also hold the notes caught by the sostenuto pedal
+    SustainAndSostenuto,
+}
+
+/// How the notes are written after the header
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    /// A character per sounding pitch and a space per frame
+    Plain,
+    /// Like `Plain`, with runs of identical frames replaced by repeat tokens
+    Rle,
+    /// Note on, note off, time shift and velocity events instead of frames
+    Events,
+    /// Bar, position, pitch, duration, velocity and tempo tokens, REMI style
+    Remi,
+}
+
+impl Encoding {
+    fn name(&self) -> &'static str {
+        match self {
+            Encoding::Plain => "plain",
+            Encoding::Rle => "rle",
+            Encoding::Events => "events",
+            Encoding::Remi => "remi",
+        }
+    }
+}
+
+/// Which notes to keep when more than max_polyphony sound in a frame, the others are dropped whole
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PolyphonyPolicy {
+    KeepHighest,
+    KeepLowest,
+    /// Keep the highest and lowest notes, then inner voices from the top down
+    MelodyAndBass,
+    /// Drop the shortest notes first, the lowest of equally long ones
+    DropShortest,
+}
+
+/// How event times in ticks are snapped to frames of the note matrix
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Quantization {
+    /// Fixed tick quantum, independent of the Header division
+    Legacy,
+    /// Snap to a beat grid with this many steps per quarter note (4 for 1/16, 6 for 1/24 triplets)
+    Grid(u32),
+    /// Snap each note to whichever of the 1/16 and 1/24 grids fits its onset best
+    Mixed,
+}
+
+impl Quantization {
+    fn name(&self) -> String {
+        match self {
+            Quantization::Legacy => "legacy".to_string(),
+            Quantization::Grid(steps_per_quarter) => format!("1/{}", steps_per_quarter * 4),
+            Quantization::Mixed => "mixed".to_string(),
+        }
+    }
+
+    /// Reads the names written by `name`
+    fn from_name(name: &str) -> Option<Quantization> {
+        match name {
+            "legacy" => Some(Quantization::Legacy),
+            "mixed" => Some(Quantization::Mixed),
+            _ => {
+                let note_value = name.strip_prefix("1/")?.parse::<u32>().ok()?;
+                (note_value > 0 && note_value % 4 == 0).then_some(Quantization::Grid(note_value / 4))
+            }
+        }
+    }
+
+    /// Spacing in frames of the grids a note may snap to
+    fn strides(&self) -> &'static [usize] {
+        match self {
+            Quantization::Legacy | Quantization::Grid(_) => &[1],
+            Quantization::Mixed => &[3, 2],
+        }
+    }
+}
+
+impl TryFrom<String> for Quantization {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        Quantization::from_name(&name).ok_or_else(|| format!("unknown quantization {:?}, expected legacy, mixed or 1/N with N a multiple of 4", name))
+    }
+}
+
+/// Distance in ticks between events and the frames they were snapped to
+#[derive(Default)]
+struct QuantizationReport {
+    events: usize,
+    total_error: f32,
+    max_error: f32,
+}
+
+impl QuantizationReport {
+    fn record(&mut self, error: f32) {
+        self.events += 1;
+        self.total_error += error;
+        self.max_error = self.max_error.max(error);
+    }
+
+    fn mean_error(&self) -> f32 {
+        if self.events == 0 { 0.0 } else { self.total_error / self.events as f32 }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct TimeSignature {
+    tick: f32,
+    numerator: u32,
+    denominator: u32,
+}
+
+impl TimeSignature {
+    const COMMON_TIME: TimeSignature = TimeSignature { tick: 0.0, numerator: 4, denominator: 4 };
+}
+
+#[derive(Clone, Copy)]
+struct ChannelPedals {
+    sustain_down: bool,
+    sostenuto_down: bool,
+    keys_down: [bool; MIDI_PITCHES],
+    sostenuto_held: [bool; MIDI_PITCHES],  // Notes sounding when sostenuto was pressed
+    pending_off: [bool; MIDI_PITCHES],     // Released keys still held by a pedal
+}
+
+impl ChannelPedals {
+    const RELEASED: ChannelPedals = ChannelPedals {
+        sustain_down: false,
+        sostenuto_down: false,
+        keys_down: [false; MIDI_PITCHES],
+        sostenuto_held: [false; MIDI_PITCHES],
+        pending_off: [false; MIDI_PITCHES],
+    };
+
+    fn holds(&self, pitch: usize) -> bool {
+        self.sustain_down || (self.sostenuto_down && self.sostenuto_held[pitch])
+    }
+}
+
+#[derive(Args)]
+pub struct CompressArgs {
+    /// midicsv file or directory of files to compress, `-` for stdin
+    #[arg(default_value = INPUT_DIR)]
+    pub input: PathBuf,
+    /// Directory for every augmented .cary file, or a single .cary file, `-` for stdout
+    #[arg(default_value = OUTPUT_DIR)]
+    pub output: PathBuf,
+    /// Worker threads for a directory of inputs, one per core by default
+    #[arg(long, short)]
+    pub jobs: Option<usize>,
+    /// Compress every file of a directory again, even those unchanged since the last run
+    #[arg(long)]
+    pub force: bool,
+    #[command(flatten)]
+    pub config: ConfigArgs,
+}
+
+/// Where compressed files are written
+pub enum OutputTarget {
+    Directory(PathBuf),  // One file per augmentation variant
+    File(PathBuf),       // Only the untransposed, unstretched variant
+    Stdout,
+}
+
+impl OutputTarget {
+    fn open(&self, name: &str) -> io::Result<Box<dyn Write>> {
+        Ok(match self {
+            OutputTarget::Directory(directory) => Box::new(BufWriter::new(File::create(directory.join(name))?)),
+            OutputTarget::File(path) => Box::new(BufWriter::new(File::create(path)?)),
+            OutputTarget::Stdout => Box::new(io::stdout().lock()),
+        })
+    }
+
+    /// Where `open` writes, for error messages
+    fn path(&self, name: &str) -> PathBuf {
+        match self {
+            OutputTarget::Directory(directory) => directory.join(name),
+            OutputTarget::File(path) => path.clone(),
+            OutputTarget::Stdout => PathBuf::from("-"),
+        }
+    }
+}
+
+pub struct MidiProcessor {
+    note_matrix: Vec<[NoteState; MIDI_PITCHES]>,
+    time_quantum: f32,
+    ticks_per_quarter: f32,
+    quantization: Quantization,
+    note_strides: [usize; MIDI_PITCHES],  // Grid chosen for each pitch's latest onset
+    onset_steps: [usize; MIDI_PITCHES],
+    quantization_report: QuantizationReport,
+    augmentation: Augmentation,
+    time_signatures: Vec<TimeSignature>,
+    tempos: Vec<(f32, u32)>,  // Tick and microseconds per quarter note of every Tempo event
+    key_signature: Option<Key>,  // From the first Key_signature event
+    normalize_key: bool,
+    meter_markers: bool,
+    write_header: bool,
+    encoding: Encoding,
+    max_pitches: usize,
+    min_pitch: i32,
+    max_time_steps: usize,
+    error_policies: ErrorPolicies,
+    notes_past_end: usize,     // Dropped because they start after max_time_steps
+    notes_above_range: usize,  // Dropped because they are at or above max_pitches
+    notes_over_polyphony: usize,  // Dropped to stay within max_polyphony
+    max_polyphony: Option<usize>,
+    polyphony_policy: PolyphonyPolicy,
+    allowed_channels: [bool; 128],
+    pedal_mode: PedalMode,
+    pedals: [ChannelPedals; MIDI_CHANNELS],
+}
+
+impl MidiProcessor {
+    pub fn new(config: &CompressorConfig) -> Self {
+        MidiProcessor {
+            note_matrix: vec![[NoteState::Off; MIDI_PITCHES]; config.max_time_steps],
+            time_quantum: 40.0,
+            ticks_per_quarter: DEFAULT_TICKS_PER_QUARTER,
+            quantization: config.quantization,
+            note_strides: [1; MIDI_PITCHES],
+            onset_steps: [0; MIDI_PITCHES],
+            quantization_report: QuantizationReport::default(),
+            augmentation: Augmentation::new(config),
+            time_signatures: Vec::new(),
+            tempos: Vec::new(),
+            key_signature: None,
+            normalize_key: config.normalize_key,
+            meter_markers: config.emit_meter_markers,
+            write_header: config.write_header,
+            encoding: config.encoding,
+            max_pitches: config.max_pitches,
+            min_pitch: config.min_pitch,
+            max_time_steps: config.max_time_steps,
+            error_policies: config.errors,
+            notes_past_end: 0,
+            notes_above_range: 0,
+            notes_over_polyphony: 0,
+            max_polyphony: config.max_polyphony,
+            polyphony_policy: config.polyphony_policy,
+            allowed_channels: [true; 128],
+            pedal_mode: config.pedal_mode,
+            pedals: [ChannelPedals::RELEASED; MIDI_CHANNELS],
+        }
+    }
+
+    pub fn process_file(&mut self, input_path: &Path, output: &OutputTarget) -> Result<FileReport, CompressError> {
+        self.reset_state();
+
+        let filename = match input_path.file_name() {
+            _ if input_path == Path::new("-") => "stdin".to_string(),
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => input_path.to_string_lossy().into_owned(),
+        };
+        let io_error = |source| CompressError::Io { path: input_path.to_path_buf(), source };
+        let reader: Box<dyn BufRead> = if input_path == Path::new("-") {
+            Box::new(io::stdin().lock())
+        } else {
+            Box::new(BufReader::new(File::open(input_path).map_err(io_error)?))
+        };
+        let mut report = FileReport::new(filename.clone());
+
+        for (index, line) in reader.split(b'\n').enumerate() {
+            let line = line.map_err(io_error)?;
+            let result = midicsv::parse_line(&line).and_then(|line| match line {
+                Some(line) => self.process_line(&line),
+                None => Ok(()),
+            });
+
+            if let Err(error) = result {
+                let error = error.at_line(index + 1);
+                match self.error_policies.for_kind(error.kind) {
+                    ErrorPolicy::Skip => report.skipped.push(error),
+                    ErrorPolicy::Fail => return Err(CompressError::Line { file: filename, error }),
+                }
+            }
+        }
+
+        if let Some(max_polyphony) = self.max_polyphony {
+            self.cap_polyphony(max_polyphony);
+        }
+
+        if self.notes_past_end > 0 {
+            report.warnings.push(format!("dropped {} notes past max_time_steps", self.notes_past_end));
+        }
+        if self.notes_above_range > 0 {
+            report.warnings.push(format!("dropped {} notes at or above max_pitches", self.notes_above_range));
+        }
+        if self.notes_over_polyphony > 0 {
+            report.warnings.push(format!("dropped {} notes over max_polyphony", self.notes_over_polyphony));
+        }
+        report.stats = self.piece_stats();
+
+        let quantization = &self.quantization_report;
+        report.notes.push(format!(
+            "quantization error: mean {:.2} ticks ({:.2}% of a quarter), max {:.2} ticks over {} events",
+            quantization.mean_error(),
+            100.0 * quantization.mean_error() / self.ticks_per_quarter,
+            quantization.max_error,
+            quantization.events
+        ));
+
+        self.generate_output_files(&filename, output, &mut report)?;
+        Ok(report)
+    }
+
+    fn process_line(&mut self, line: &MidiCsvLine) -> Result<(), LineError> {
+        let tick = line.tick as f32;
+
+        match line.record {
+            // Negative divisions are SMPTE timecodes, which have no notion of a beat
+            MidiCsvRecord::Header { division, .. } if division > 0 => self.ticks_per_quarter = division as f32,
+            MidiCsvRecord::Tempo(tempo) => self.tempos.push((tick, tempo)),
+            MidiCsvRecord::KeySignature { sharps, minor } if self.key_signature.is_none() => {
+                self.key_signature = Some(Key::from_signature(sharps, minor))
+            }
+            MidiCsvRecord::TimeSignature { numerator, denominator_power, .. } => {
+                self.process_time_signature(tick, numerator, denominator_power)?
+            }
+            // Only allow piano-like instruments (0-7)
+            MidiCsvRecord::ProgramChange { channel, program } => self.allowed_channels[channel as usize] = program <= 7,
+            MidiCsvRecord::StartTrack | MidiCsvRecord::EndTrack => self.process_track_boundary(tick),
+            MidiCsvRecord::ControlChange { channel, controller, value } if line.track <= 8 => {
+                self.process_pedal_event(tick, channel as usize, controller, value)
+            }
+            MidiCsvRecord::NoteOn { channel, note, velocity } if line.track <= 8 => {
+                self.process_note_event(tick, channel as usize, note as usize, Some(velocity).filter(|velocity| *velocity > 0))
+            }
+            MidiCsvRecord::NoteOff { channel, note, .. } if line.track <= 8 => {
+                self.process_note_event(tick, channel as usize, note as usize, None)
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn process_time_signature(&mut self, tick: f32, numerator: u8, denominator_power: u8) -> Result<(), LineError> {
+        // The denominator is stored as a power of two
+        if numerator == 0 || denominator_power >= 8 {
+            return Err(LineError::out_of_range(format!("time signature {}/2^{}", numerator, denominator_power)));
+        }
+        self.time_signatures.push(TimeSignature { tick, numerator: numerator as u32, denominator: 1 << denominator_power });
+        Ok(())
+    }
+
+    fn process_track_boundary(&mut self, tick: f32) {
+        // Pedals never carry over from one track to the next
+        for channel in 0..MIDI_CHANNELS {
+            self.pedals[channel].sustain_down = false;
+            self.pedals[channel].sostenuto_down = false;
+            self.release_pending_notes(tick, channel);
+        }
+        self.pedals = [ChannelPedals::RELEASED; MIDI_CHANNELS];
+    }
+
+    fn process_pedal_event(&mut self, tick: f32, channel: usize, controller: u8, value: u8) {
+        if self.pedal_mode == PedalMode::Raw || !self.allowed_channels[channel] {
+            return;
+        }
+
+        let pressed = value >= PEDAL_DOWN_THRESHOLD;
+        let pedals = &mut self.pedals[channel];
+
+        match controller {
+            SUSTAIN_CONTROLLER if pressed => pedals.sustain_down = true,
+            SUSTAIN_CONTROLLER => {
+                pedals.sustain_down = false;
+                self.release_pending_notes(tick, channel);
+            }
+            SOSTENUTO_CONTROLLER if self.pedal_mode != PedalMode::SustainAndSostenuto => (),
+            SOSTENUTO_CONTROLLER if pressed && !pedals.sostenuto_down => {
+                // Sostenuto only catches notes that are sounding at the moment it is pressed
+                pedals.sostenuto_down = true;
+                for pitch in 0..MIDI_PITCHES {
+                    pedals.sostenuto_held[pitch] = pedals.keys_down[pitch] || pedals.pending_off[pitch];
+                }
+            }
+            SOSTENUTO_CONTROLLER if pressed => (),
+            SOSTENUTO_CONTROLLER => {
+                pedals.sostenuto_down = false;
+                pedals.sostenuto_held = [false; MIDI_PITCHES];
+                self.release_pending_notes(tick, channel);
+            }
+            _ => (),
+        }
+    }
+
+    /// Ends every note on `channel` that was waiting for a pedal and is no longer held by one
+    fn release_pending_notes(&mut self, tick: f32, channel: usize) {
+        for pitch in 0..MIDI_PITCHES {
+            let pedals = &self.pedals[channel];
+            if pedals.pending_off[pitch] && !pedals.holds(pitch) {
+                self.pedals[channel].pending_off[pitch] = false;
+                self.end_note(tick, pitch);
+            }
+        }
+    }
+
+    /// Presses a key when there is a velocity, a note on with velocity 0 releases it like a note off
+    fn process_note_event(&mut self, tick: f32, channel: usize, pitch: usize, velocity: Option<u8>) {
+        if !self.allowed_channels[channel] {
+            return;
+        }
+        // Only note ons are counted, so a dropped note isn't counted again for its release
+        if tick / self.ticks_per_step() >= self.max_time_steps as f32 {
+            self.notes_past_end += velocity.is_some() as usize;
+            return;
+        }
+        if pitch >= self.max_pitches {
+            self.notes_above_range += velocity.is_some() as usize;
+            return;
+        }
+
+        match velocity {
+            Some(velocity) => self.press_key(tick, channel, pitch, velocity),
+            None => self.release_key(tick, channel, pitch),
+        }
+    }
+
+    fn ticks_per_step(&self) -> f32 {
+        match self.quantization {
+            Quantization::Legacy => self.time_quantum,
+            Quantization::Grid(steps_per_quarter) => self.ticks_per_quarter / steps_per_quarter as f32,
+            Quantization::Mixed => self.ticks_per_quarter / MIXED_STEPS_PER_QUARTER as f32,
+        }
+    }
+
+    /// Snaps `tick` to the closest frame on a grid spaced `stride` frames apart
+    fn snap(&self, tick: f32, stride: usize) -> (usize, f32) {
+        let ticks_per_step = self.ticks_per_step();
+        let step = match self.quantization {
+            Quantization::Legacy => (tick / ticks_per_step) as usize,
+            _ => (tick / (ticks_per_step * stride as f32)).round() as usize * stride,
+        };
+        (step, (tick - step as f32 * ticks_per_step).abs())
+    }
+
+    fn quantize_onset(&mut self, tick: f32, pitch: usize) -> usize {
+        let (step, error, stride) = self.quantization.strides()
+            .iter()
+            .map(|&stride| {
+                let (step, error) = self.snap(tick, stride);
+                (step, error, stride)
+            })
+            .fold(None, |best: Option<(usize, f32, usize)>, candidate| match best {
+                Some(best) if best.1 <= candidate.1 => Some(best),
+                _ => Some(candidate),
+            })
+            .expect("Every quantization has at least one grid");
+
+        self.quantization_report.record(error);
+        self.note_strides[pitch] = stride;
+        self.onset_steps[pitch] = step;
+        step.min(self.max_time_steps - 1)
+    }
+
+    fn quantize_release(&mut self, tick: f32, pitch: usize) -> usize {
+        let (mut step, error) = self.snap(tick, self.note_strides[pitch]);
+        self.quantization_report.record(error);
+
+        // Rounding must not swallow a note entirely
+        if self.quantization != Quantization::Legacy {
+            step = step.max(self.onset_steps[pitch] + 1);
+        }
+        step.min(self.max_time_steps - 1)
+    }
+
+    fn press_key(&mut self, tick: f32, channel: usize, pitch: usize, velocity: u8) {
+        let time = self.quantize_onset(tick, pitch);
+        if self.pedal_mode != PedalMode::Raw && channel < MIDI_CHANNELS {
+            let pedals = &mut self.pedals[channel];
+            pedals.keys_down[pitch] = true;
+
+            // Striking a note again while a pedal holds it ends the held one
+            if pedals.pending_off[pitch] {
+                pedals.pending_off[pitch] = false;
+                self.handle_note_off(time, pitch);
+            }
+        }
+        self.handle_note_on(time, pitch, velocity);
+    }
+
+    fn release_key(&mut self, tick: f32, channel: usize, pitch: usize) {
+        if self.pedal_mode != PedalMode::Raw && channel < MIDI_CHANNELS {
+            let pedals = &mut self.pedals[channel];
+            pedals.keys_down[pitch] = false;
+
+            if pedals.holds(pitch) {
+                pedals.pending_off[pitch] = true;
+                return;
+            }
+        }
+        self.end_note(tick, pitch);
+    }
+
+    fn end_note(&mut self, tick: f32, pitch: usize) {
+        let time = self.quantize_release(tick, pitch);
+        self.handle_note_off(time, pitch);
+    }
+
+    fn handle_note_on(&mut self, time: usize, pitch: usize, velocity: u8) {
+        if self.note_matrix[time][pitch] == NoteState::Off {
+            self.note_matrix[time][pitch] = NoteState::On(velocity);
+        }
+    }
+
+    fn handle_note_off(&mut self, time: usize, pitch: usize) {
+        // Find when the note was last played
+        let mut last_on_time = time.saturating_sub(1);
+        while last_on_time > 0 && !matches!(self.note_matrix[last_on_time][pitch], NoteState::On(_)) {
+            last_on_time -= 1;
+        }
+
+        // Mark all times between last_on and now as sustained
+        if matches!(self.note_matrix[last_on_time][pitch], NoteState::On(_)) {
+            for t in last_on_time..time {
+                if self.note_matrix[t][pitch] == NoteState::Off {
+                    self.note_matrix[t][pitch] = NoteState::Sustained;
+                }
+            }
+        }
+    }
+
+    /// Drops whole notes, frame by frame, until no frame has more than `max_polyphony` sounding
+    fn cap_polyphony(&mut self, max_polyphony: usize) {
+        for time in 0..self.frame_count() {
+            // Sounding notes as pitch, first frame and last frame
+            let mut notes: Vec<(usize, usize, usize)> = (0..MIDI_PITCHES)
+                .filter(|pitch| self.note_matrix[time][*pitch] != NoteState::Off)
+                .map(|pitch| {
+                    let (start, end) = self.note_extent(time, pitch);
+                    (pitch, start, end)
+                })
+                .collect();
+            if notes.len() <= max_polyphony {
+                continue;
+            }
+
+            // Order by preference, the notes after the first max_polyphony are dropped
+            match self.polyphony_policy {
+                PolyphonyPolicy::KeepHighest => notes.reverse(),
+                PolyphonyPolicy::KeepLowest => (),
+                PolyphonyPolicy::MelodyAndBass => {
+                    let bass = notes.remove(0);
+                    notes.reverse();
+                    notes.insert(1.min(notes.len()), bass);
+                }
+                PolyphonyPolicy::DropShortest => notes.sort_by_key(|&(pitch, start, end)| (Reverse(end - start), Reverse(pitch))),
+            }
+
+            for &(pitch, start, end) in &notes[max_polyphony..] {
+                for notes in &mut self.note_matrix[start..=end] {
+                    notes[pitch] = NoteState::Off;
+                }
+                self.notes_over_polyphony += 1;
+            }
+        }
+    }
+
+    /// First and last frame of the note sounding at `time`
+    fn note_extent(&self, time: usize, pitch: usize) -> (usize, usize) {
+        let mut start = time;
+        while start > 0 && self.note_matrix[start][pitch] == NoteState::Sustained && self.note_matrix[start - 1][pitch] != NoteState::Off {
+            start -= 1;
+        }
+        let mut end = time;
+        while self.note_matrix.get(end + 1).is_some_and(|notes| notes[pitch] == NoteState::Sustained) {
+            end += 1;
+        }
+        (start, end)
+    }
+
+    /// Marker to write before each frame: a bar line, a beat, or nothing
+    fn meter_markers(&self, frame_count: usize) -> Vec<Option<char>> {
+        let mut markers = vec![None; frame_count];
+
+        let mut time_signatures = self.time_signatures.clone();
+        time_signatures.sort_by(|a, b| a.tick.total_cmp(&b.tick));
+        if time_signatures.first().is_none_or(|first| first.tick > 0.0) {
+            time_signatures.insert(0, TimeSignature::COMMON_TIME);
+        }
+
+        let end_tick = frame_count as f32 * self.ticks_per_step();
+        for (index, signature) in time_signatures.iter().enumerate() {
+            let segment_end = time_signatures.get(index + 1).map_or(end_tick, |next| next.tick.min(end_tick));
+            let beat_ticks = self.ticks_per_quarter * 4.0 / signature.denominator as f32;
+
+            let mut beat = 0;
+            let mut tick = signature.tick;
+            while tick < segment_end {
+                let (frame, _) = self.snap(tick, 1);
+                if frame < frame_count {
+                    if beat % signature.numerator == 0 {
+                        markers[frame] = Some(BAR_MARKER);
+                    } else if markers[frame].is_none() {
+                        markers[frame] = Some(BEAT_MARKER);
+                    }
+                }
+                beat += 1;
+                tick = signature.tick + beat as f32 * beat_ticks;
+            }
+        }
+        markers
+    }
+
+    /// Tempo of every frame a Tempo event snaps to, the last one when several do
+    fn tempo_changes(&self, frame_count: usize) -> Vec<Option<u32>> {
+        let mut tempos = vec![None; frame_count];
+        for &(tick, tempo) in &self.tempos {
+            let (frame, _) = self.snap(tick, 1);
+            if frame < frame_count {
+                tempos[frame] = Some(tempo);
+            }
+        }
+        tempos
+    }
+
+    /// Frames up to the last one with a sounding note
+    fn frame_count(&self) -> usize {
+        self.note_matrix
+            .iter()
+            .rposition(|notes| notes.iter().any(|state| *state != NoteState::Off))
+            .map_or(0, |last| last + 1)
+    }
+
+    fn piece_stats(&self) -> PieceStats {
+        let mut stats = PieceStats {
+            frames: self.frame_count(),
+            dropped_notes: self.notes_past_end + self.notes_above_range + self.notes_over_polyphony,
+            ..PieceStats::default()
+        };
+        for notes in &self.note_matrix[..stats.frames] {
+            let mut polyphony = 0;
+            for (pitch, state) in notes.iter().enumerate().filter(|(_, state)| **state != NoteState::Off) {
+                polyphony += 1;
+                stats.notes += matches!(state, NoteState::On(_)) as usize;
+                stats.lowest_pitch = Some(stats.lowest_pitch.map_or(pitch, |lowest| lowest.min(pitch)));
+                stats.highest_pitch = Some(stats.highest_pitch.map_or(pitch, |highest| highest.max(pitch)));
+            }
+            stats.max_polyphony = stats.max_polyphony.max(polyphony);
+        }
+        stats
+    }
+
+    /// The key signature when the file has one, otherwise estimated from how long each pitch class sounds
+    fn key(&self, frames: &[[NoteState; MIDI_PITCHES]]) -> Option<Key> {
+        if self.key_signature.is_some() {
+            return self.key_signature;
+        }
+        let mut durations = [0.0; 12];
+        for notes in frames {
+            for (pitch, state) in notes.iter().enumerate() {
+                if *state != NoteState::Off {
+                    durations[pitch % 12] += 1.0;
+                }
+            }
+        }
+        Key::estimate(&durations)
+    }
+
+    fn generate_output_files(&self, filename: &str, output: &OutputTarget, report: &mut FileReport) -> Result<(), CompressError> {
+        let frame_count = self.frame_count();
+        let frames = &self.note_matrix[..frame_count];
+        let key = self.key(frames);
+        // REMI needs to know where bars start even when the frames don't show it
+        let markers = if self.meter_markers || self.encoding == Encoding::Remi {
+            self.meter_markers(frame_count)
+        } else {
+            vec![None; frame_count]
+        };
+        let tempos = self.tempo_changes(frame_count);
+        let mut rng = rand::rng();
+        let mut bytes = (0, 0);  // Written, and what the plain format would have taken
+
+        // A single output file has room for one variant only, the configured one closest to the original
+        let (tempo_stretches, mut transpositions) = match output {
+            OutputTarget::Directory(_) => (self.augmentation.tempo_stretches.clone(), self.augmentation.transpositions.clone()),
+            OutputTarget::File(_) | OutputTarget::Stdout => {
+                let transpositions = &self.augmentation.transpositions;
+                let transposition = 0.clamp(*transpositions.start(), *transpositions.end());
+                let stretch = self.augmentation.tempo_stretches.iter()
+                    .copied()
+                    .min_by(|a, b| a.ln().abs().total_cmp(&b.ln().abs()))
+                    .unwrap_or(1.0);
+                (vec![stretch], transposition..=transposition)
+            }
+        };
+        if self.normalize_key {
+            let shift = key.map_or(0, |key| key.normalizing_shift());
+            transpositions = shift..=shift;
+        }
+
+        for &stretch in &tempo_stretches {
+            for transposition in transpositions.clone() {
+                let mut piece = self.augmentation.stretch(frames, &markers, &tempos, stretch);
+                self.augmentation.jitter_velocities(&mut piece, &mut rng);
+                self.augmentation.crop(&mut piece, &mut rng);
+
+                let output_name = Self::output_name(filename, (!self.normalize_key).then_some(transposition), stretch);
+
+                let write = || -> io::Result<(usize, usize)> {
+                    let mut output_file = output.open(&output_name)?;
+                    if self.write_header {
+                        self.write_cary_header(&mut output_file, filename, transposition, stretch, key, piece.start_frame)?;
+                    }
+                    let pitch_offset = self.min_pitch - transposition;
+                    let sizes = match self.encoding {
+                        Encoding::Plain | Encoding::Rle => {
+                            Self::write_cary(&mut output_file, &piece, pitch_offset, self.encoding == Encoding::Rle, self.write_header)?
+                        }
+                        Encoding::Events => {
+                            let written = events::write_events(&mut output_file, &piece, transposition, |pitch| {
+                                Self::cary_char(pitch, pitch_offset).is_some()
+                            })?;
+                            (written, Self::write_cary(&mut io::sink(), &piece, pitch_offset, false, self.write_header)?.1)
+                        }
+                        Encoding::Remi => {
+                            let written = remi::write_remi(&mut output_file, &piece, transposition, |pitch| {
+                                Self::cary_char(pitch, pitch_offset).is_some()
+                            })?;
+                            (written, Self::write_cary(&mut io::sink(), &piece, pitch_offset, false, self.write_header)?.1)
+                        }
+                    };
+                    output_file.flush()?;
+                    Ok(sizes)
+                };
+                let (written, plain) = write().map_err(|source| CompressError::Io { path: output.path(&output_name), source })?;
+                report.notes.push(format!("wrote {}", output.path(&output_name).display()));
+                bytes = (bytes.0 + written, bytes.1 + plain);
+            }
+        }
+
+        if self.encoding != Encoding::Plain {
+            report.notes.push(format!(
+                "{} encoding: {} bytes of notes instead of {} plain, compression ratio {:.2}",
+                self.encoding.name(),
+                bytes.0,
+                bytes.1,
+                bytes.1 as f32 / bytes.0.max(1) as f32
+            ));
+        }
+        Ok(())
+    }
+
+    /// Name of one augmented variant in an output directory, unstretched outputs keep the original naming.
+    /// Pieces normalized to C major or A minor have no transposition in their name.
+    fn output_name(filename: &str, transposition: Option<i32>, stretch: f32) -> String {
+        let variant = transposition.map_or_else(|| "key".to_string(), |transposition| transposition.to_string());
+        if stretch == 1.0 {
+            format!("{}_{}.cary", filename, variant)
+        } else {
+            format!("{}_{}_x{}.cary", filename, variant, stretch)
+        }
+    }
+
+    /// Writes the metadata block the decompressor needs to restore timing and pitch.
+    /// Frames list their pitches in ascending order, so no frame can read `%cary`.
+    fn write_cary_header(
+        &self,
+        output_file: &mut impl Write,
+        filename: &str,
+        transposition: i32,
+        stretch: f32,
+        key: Option<Key>,
+        start_frame: usize,
+    ) -> io::Result<()> {
+        let mut header = format!("%cary {}\n", CARY_FORMAT_VERSION);
+        header += &format!("%source {}\n", filename);
+        header += &format!("%transposition {}\n", transposition);
+        // Key of the source, before transposition
+        if let Some(key) = key {
+            header += &format!("%key {}\n", key.describe());
+        }
+        header += &format!("%tempo_stretch {}\n", stretch);
+        header += &format!("%grid {}\n", self.quantization.name());
+        header += &format!("%ticks_per_frame {}\n", self.ticks_per_step());
+        header += &format!("%division {}\n", self.ticks_per_quarter);
+        // Ticks count from the first frame written, in source ticks as the decompressor stretches them
+        let start_tick = start_frame as f32 * self.ticks_per_step() / stretch;
+        for (tick, (_, tempo)) in from_start_tick(&self.tempos, |(tick, _)| *tick, start_tick) {
+            header += &format!("%tempo {} {}\n", tick, tempo);
+        }
+        for (tick, signature) in from_start_tick(&self.time_signatures, |signature| signature.tick, start_tick) {
+            header += &format!("%time_signature {} {} {}\n", tick, signature.numerator, signature.denominator);
+        }
+        header += &format!("%pitch_offset {}\n", self.min_pitch);
+        if self.encoding != Encoding::Plain {
+            header += &format!("%encoding {}\n", self.encoding.name());
+        }
+        header += "%end\n";
+
+        write!(output_file, "{}", header)
+    }
+
+    /// `pitch_offset` is the (transposed) MIDI pitch written as '!'. Silent frames are left out unless
+    /// `keep_silence`, which files with a header need so their frames stay in step with the header's ticks.
+    /// Returns the bytes written and the bytes the frames take without run-length encoding.
+    fn write_cary(
+        output_file: &mut impl Write,
+        piece: &AugmentedPiece,
+        pitch_offset: i32,
+        run_length_encoding: bool,
+        keep_silence: bool,
+    ) -> io::Result<(usize, usize)> {
+        let mut written = 0;
+        let mut plain = 0;
+        let mut previous_line = String::new();
+        let mut repeats = 0;
+
+        for (notes, marker) in piece.frames.iter().zip(piece.markers.iter()) {
+            let mut output_line = String::new();
+
+            // Convert active notes to ASCII characters
+            for (pitch, state) in notes.iter().enumerate() {
+                if let Some(char) = Self::cary_char(pitch, pitch_offset).filter(|_| *state != NoteState::Off) {
+                    output_line.push(char);
+                }
+            }
+
+            // Add formatting, a silent frame is a bare space
+            if !output_line.is_empty() || keep_silence {
+                output_line.push(' ');
+            }
+            if let Some(marker) = marker {
+                output_line.insert(0, *marker);
+            }
+            plain += output_line.len();
+
+            // A frame starting a bar or beat is written out, so the marker is kept
+            if run_length_encoding && marker.is_none() && !output_line.is_empty() && output_line == previous_line {
+                repeats += 1;
+                continue;
+            }
+            let tokens = repeat_tokens(repeats) + &output_line;
+            write!(output_file, "{}", tokens)?;
+            written += tokens.len();
+            previous_line = output_line.trim_start_matches([BAR_MARKER, BEAT_MARKER]).to_string();
+            repeats = 0;
+        }
+
+        let tokens = repeat_tokens(repeats);
+        write!(output_file, "{}", tokens)?;
+        Ok((written + tokens.len(), plain))
+    }
+
+    /// Character a pitch is written as, None for pitches the format has no room for
+    fn cary_char(pitch: usize, pitch_offset: i32) -> Option<char> {
+        let ascii_code = 33 + pitch as i32 - pitch_offset;
+        (pitch >= 24 && (33..=126).contains(&ascii_code)).then_some(ascii_code as u8 as char)
+    }
+
+    fn reset_state(&mut self) {
+        self.allowed_channels = [true; 128];
+        self.pedals = [ChannelPedals::RELEASED; MIDI_CHANNELS];
+        self.ticks_per_quarter = DEFAULT_TICKS_PER_QUARTER;
+        self.note_strides = [1; MIDI_PITCHES];
+        self.onset_steps = [0; MIDI_PITCHES];
+        self.quantization_report = QuantizationReport::default();
+        self.time_signatures.clear();
+        self.tempos.clear();
+        self.key_signature = None;
+        self.notes_past_end = 0;
+        self.notes_above_range = 0;
+        self.notes_over_polyphony = 0;
+        self.note_matrix = vec![[NoteState::Off; MIDI_PITCHES]; self.max_time_steps];
+    }
+}
+
+/// Timed events with their ticks counted from `start_tick`, in order. The one in effect at `start_tick`
+/// moves there and earlier ones are dropped, so a cropped piece keeps the tempo and meter it starts in.
+fn from_start_tick<T>(events: &[T], tick: impl Fn(&T) -> f32, start_tick: f32) -> Vec<(f32, &T)> {
+    let mut events: Vec<&T> = events.iter().collect();
+    events.sort_by(|a, b| tick(a).total_cmp(&tick(b)));
+    let in_effect = events.iter().rposition(|event| tick(event) <= start_tick).unwrap_or(0);
+    events[in_effect..]
+        .iter()
+        .map(|event| ((tick(event) - start_tick).max(0.0), *event))
+        .collect()
+}
+
+impl Default for MidiProcessor {
+    fn default() -> Self {
+        Self::new(&CompressorConfig::default())
+    }
+}
+
+/// Compresses a single file, or every file of a directory into another directory
+pub fn run(args: &CompressArgs) -> io::Result<()> {
+    let config: CompressorConfig = args.config.load()?;
+
+    if !args.input.is_dir() {
+        let output = if args.output == Path::new("-") {
+            OutputTarget::Stdout
+        } else if args.output.is_dir() {
+            OutputTarget::Directory(args.output.clone())
+        } else {
+            OutputTarget::File(args.output.clone())
+        };
+        MidiProcessor::new(&config).process_file(&args.input, &output)?.print();
+        return Ok(());
+    }
+
+    fs::create_dir_all(&args.output)?;
+    let options = BatchOptions {
+        jobs: args.jobs.unwrap_or_else(|| thread::available_parallelism().map_or(1, |jobs| jobs.get())),
+        force: args.force,
+    };
+    compress_directory(&args.input, &args.output, &config, &options)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compresses midicsv text to a temporary file, returning the report and the written .cary text
+    pub(crate) fn compress(midi_csv: &str, config: &CompressorConfig) -> (FileReport, String) {
+        let directory = std::env::temp_dir().join(format!("compressor_test_{}_{:?}", std::process::id(), thread::current().id()));
+        fs::create_dir_all(&directory).unwrap();
+        let input = directory.join("test.csv");
+        fs::write(&input, midi_csv).unwrap();
+
+        let output = directory.join("test.cary");
+        let report = MidiProcessor::new(config).process_file(&input, &OutputTarget::File(output.clone()));
+        let written = fs::read_to_string(&output);
+        fs::remove_dir_all(&directory).unwrap();
+        (report.unwrap(), written.unwrap())
+    }
+
+    #[test]
+    fn dropped_notes_are_counted_once() {
+        let config = CompressorConfig { max_time_steps: 10, ..CompressorConfig::default() };
+        // Frames are 40 ticks, so notes from tick 400 on are past the end
+        let (report, _) = compress(
+            "0, 0, Header, 1, 2, 384\n\
+             2, 0, Note_on_c, 0, 60, 100\n\
+             2, 40, Note_off_c, 0, 60, 0\n\
+             2, 400, Note_on_c, 0, 62, 100\n\
+             2, 440, Note_on_c, 0, 62, 0\n\
+             2, 480, Note_on_c, 0, 64, 100\n\
+             2, 520, Note_off_c, 0, 64, 0\n\
+             2, 0, Note_on_c, 0, 127, 100\n\
+             2, 40, Note_off_c, 0, 127, 0\n",
+            &config,
+        );
+        assert_eq!(report.warnings, ["dropped 2 notes past max_time_steps", "dropped 1 notes at or above max_pitches"]);
+        assert_eq!(report.stats.dropped_notes, 3);
+    }
+
+    #[test]
+    fn key_signature_is_preferred_to_the_estimate() {
+        let config = CompressorConfig { normalize_key: true, ..CompressorConfig::default() };
+        // An A minor chord
+        let notes = "2, 0, Note_on_c, 0, 57, 100\n\
+                     2, 0, Note_on_c, 0, 60, 100\n\
+                     2, 0, Note_on_c, 0, 64, 100\n\
+                     2, 400, Note_off_c, 0, 57, 0\n\
+                     2, 400, Note_off_c, 0, 60, 0\n\
+                     2, 400, Note_off_c, 0, 64, 0\n";
+        let (_, cary) = compress(&format!("0, 0, Header, 1, 2, 384\n{}", notes), &config);
+        assert!(cary.contains("%key A minor estimated\n"), "{}", cary);
+        assert!(cary.contains("%transposition 0\n"), "{}", cary);
+
+        let (_, cary) = compress(&format!("0, 0, Header, 1, 2, 384\n1, 0, Key_signature, 2, \"major\"\n{}", notes), &config);
+        assert!(cary.contains("%key D major signature\n"), "{}", cary);
+        // Moved down to C major
+        assert!(cary.contains("%transposition -2\n"), "{}", cary);
+    }
+}
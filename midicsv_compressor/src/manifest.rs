@@ -0,0 +1,115 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Constants
+pub const MANIFEST_FILE: &str = "manifest.csv";  // Written next to the .cary files of a directory run
+const COLUMNS: &str = "file,content_hash,status,frames,notes,lowest_pitch,highest_pitch,max_polyphony,skipped_lines,dropped_notes";
+
+/// What was extracted from one input file, before augmentation
+#[derive(Clone, Default, Debug)]
+pub struct PieceStats {
+    pub frames: usize,
+    pub notes: usize,
+    pub lowest_pitch: Option<usize>,  // MIDI note numbers
+    pub highest_pitch: Option<usize>,
+    pub max_polyphony: usize,
+    pub dropped_notes: usize,  // Valid notes the compressor could not keep
+}
+
+/// One row per input file of a directory run
+#[derive(Default)]
+pub struct Manifest {
+    rows: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    /// Reads the manifest of an earlier run, an empty one if there is none
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let rows = text
+            .lines()
+            .skip(1)
+            .filter_map(|line| Some((first_field(line)?, line.to_string())))
+            .collect();
+        Ok(Manifest { rows })
+    }
+
+    pub fn compressed(&mut self, file: &str, content_hash: u64, stats: &PieceStats, skipped_lines: usize) {
+        let pitch = |pitch: Option<usize>| pitch.map_or(String::new(), |pitch| pitch.to_string());
+        let row = format!(
+            "{},{:016x},compressed,{},{},{},{},{},{},{}",
+            csv_field(file),
+            content_hash,
+            stats.frames,
+            stats.notes,
+            pitch(stats.lowest_pitch),
+            pitch(stats.highest_pitch),
+            stats.max_polyphony,
+            skipped_lines,
+            stats.dropped_notes
+        );
+        self.rows.insert(file.to_string(), row);
+    }
+
+    /// Whether the run that compressed the file recorded its stats. An interrupted run never wrote
+    /// its manifest, so the files it compressed have none and are compressed again.
+    pub fn has_stats(&self, file: &str) -> bool {
+        let row = self.rows.get(file).and_then(|row| row.get(csv_field(file).len()..));
+        row.and_then(|rest| rest.split(',').nth(2)) == Some("compressed")
+    }
+
+    /// Keeps the stats of the run that compressed the file
+    pub fn unchanged(&mut self, file: &str, previous: &Manifest) {
+        if let Some(row) = previous.rows.get(file) {
+            self.rows.insert(file.to_string(), row.clone());
+        }
+    }
+
+    pub fn failed(&mut self, file: &str) {
+        self.rows.insert(file.to_string(), format!("{},,failed,,,,,,,", csv_field(file)));
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut text = format!("{}\n", COLUMNS);
+        for row in self.rows.values() {
+            text += row;
+            text.push('\n');
+        }
+        fs::write(path, text)
+    }
+}
+
+/// Quotes a field holding a comma, quote or line break, doubling its quotes
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn first_field(line: &str) -> Option<String> {
+    let Some(quoted) = line.strip_prefix('"') else {
+        return line.split(',').next().map(str::to_string);
+    };
+
+    let mut field = String::new();
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if chars.as_str().starts_with('"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => return Some(field),
+            c => field.push(c),
+        }
+    }
+    None
+}
@@ -0,0 +1,440 @@
+//! Parser for the text format written by midicsv, one record per line:
+//! `Track, Time, Type[, fields...]`, with text in double quotes.
+
+use std::str::FromStr;
+use crate::error::{LineError, LineErrorKind};
+
+// Constants
+const MAX_CHANNEL: u32 = 15;
+const MAX_DATA_VALUE: u32 = 127;  // Notes, velocities, controllers, programs
+const MAX_PITCH_BEND: u32 = 16_383;
+
+/// One non-empty, non-comment line of a midicsv file
+#[derive(Clone, Debug, PartialEq)]
+pub struct MidiCsvLine {
+    pub track: u32,
+    pub tick: u64,
+    pub record: MidiCsvRecord,
+}
+
+/// Every record type midicsv writes
+#[derive(Clone, Debug, PartialEq)]
+pub enum MidiCsvRecord {
+    Header { format: u16, tracks: u16, division: i32 },  // Negative divisions are SMPTE timecodes
+    StartTrack,
+    EndTrack,
+    EndOfFile,
+    Text { kind: TextKind, text: String },  // Latin-1, escapes already decoded
+    SequenceNumber(u16),
+    MidiPort(u8),
+    ChannelPrefix(u8),
+    TimeSignature { numerator: u8, denominator_power: u8, click: u8, notes_per_quarter: u8 },
+    KeySignature { sharps: i8, minor: bool },  // Flats are negative
+    Tempo(u32),  // Microseconds per quarter note
+    SmpteOffset { hour: u8, minute: u8, second: u8, frame: u8, fractional_frame: u8 },
+    SequencerSpecific(Vec<u8>),
+    UnknownMetaEvent { meta_type: u8, data: Vec<u8> },
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    PitchBend { channel: u8, value: u16 },  // 8192 is centered
+    ControlChange { channel: u8, controller: u8, value: u8 },
+    ProgramChange { channel: u8, program: u8 },
+    ChannelAftertouch { channel: u8, value: u8 },
+    PolyAftertouch { channel: u8, note: u8, value: u8 },
+    SystemExclusive(Vec<u8>),
+    SystemExclusivePacket(Vec<u8>),
+}
+
+/// The meta events holding a single text string
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TextKind {
+    Text,
+    Copyright,
+    Title,
+    InstrumentName,
+    Lyric,
+    Marker,
+    CuePoint,
+}
+
+/// A field as written in the file, quoted strings are kept apart so a number in quotes stays text
+#[derive(Debug)]
+enum Field {
+    Plain(String),
+    Quoted(String),
+}
+
+/// Parses one line, `None` for blank lines and `#` or `;` comments.
+/// Lines are bytes because midicsv writes text in Latin-1.
+pub fn parse_line(line: &[u8]) -> Result<Option<MidiCsvLine>, LineError> {
+    let line = line.trim_ascii();
+    if line.is_empty() || line[0] == b'#' || line[0] == b';' {
+        return Ok(None);
+    }
+
+    let fields = Fields::split(line)?;
+    let track = fields.number(0, "track")?;
+    let tick = fields.number(1, "time")?;
+    let record = fields.record()?;
+    Ok(Some(MidiCsvLine { track, tick, record }))
+}
+
+struct Fields {
+    fields: Vec<Field>,
+}
+
+impl Fields {
+    /// Splits on commas outside quotes, trimming the whitespace around each field
+    fn split(line: &[u8]) -> Result<Fields, LineError> {
+        let mut fields = Vec::new();
+        let mut rest = line;
+
+        loop {
+            rest = rest.trim_ascii_start();
+            let field = if rest.first() == Some(&b'"') {
+                let (text, after) = Self::quoted(&rest[1..])?;
+                rest = after.trim_ascii_start();
+                if !rest.is_empty() && rest[0] != b',' {
+                    return Err(invalid_text("text after a closing quote".to_string()));
+                }
+                Field::Quoted(text)
+            } else {
+                let end = rest.iter().position(|byte| *byte == b',').unwrap_or(rest.len());
+                let plain = std::str::from_utf8(rest[..end].trim_ascii())
+                    .map_err(|_| invalid_text("unquoted field is not ASCII".to_string()))?;
+                let field = Field::Plain(plain.to_string());
+                rest = &rest[end..];
+                field
+            };
+            fields.push(field);
+
+            match rest.split_first() {
+                Some((b',', after)) => rest = after,
+                _ => break,
+            }
+        }
+        Ok(Fields { fields })
+    }
+
+    /// Reads a string after its opening quote, returning it and what follows the closing quote
+    fn quoted(bytes: &[u8]) -> Result<(String, &[u8]), LineError> {
+        let mut text = String::new();
+        let mut index = 0;
+
+        while index < bytes.len() {
+            match bytes[index] {
+                // Quotes inside text are doubled
+                b'"' if bytes.get(index + 1) == Some(&b'"') => {
+                    text.push('"');
+                    index += 2;
+                }
+                b'"' => return Ok((text, &bytes[index + 1..])),
+                b'\\' if bytes.get(index + 1) == Some(&b'\\') => {
+                    text.push('\\');
+                    index += 2;
+                }
+                // Non graphic characters are written as three octal digits
+                b'\\' => {
+                    let digits = bytes
+                        .get(index + 1..index + 4)
+                        .and_then(|digits| std::str::from_utf8(digits).ok())
+                        .and_then(|digits| u8::from_str_radix(digits, 8).ok())
+                        .ok_or_else(|| invalid_text("backslash not followed by three octal digits".to_string()))?;
+                    text.push(digits as char);
+                    index += 4;
+                }
+                byte => {
+                    text.push(byte as char);
+                    index += 1;
+                }
+            }
+        }
+        Err(invalid_text("text has no closing quote".to_string()))
+    }
+
+    fn record_type(&self) -> &str {
+        match self.fields.get(2) {
+            Some(Field::Plain(record_type)) => record_type,
+            _ => "",
+        }
+    }
+
+    fn record(&self) -> Result<MidiCsvRecord, LineError> {
+        let text = |kind| Ok(MidiCsvRecord::Text { kind, text: self.text(3)? });
+
+        match self.record_type() {
+            "Header" => Ok(MidiCsvRecord::Header {
+                format: self.number(3, "format")?,
+                tracks: self.number(4, "track count")?,
+                division: self.number(5, "division")?,
+            }),
+            "Start_track" => Ok(MidiCsvRecord::StartTrack),
+            "End_track" => Ok(MidiCsvRecord::EndTrack),
+            "End_of_file" => Ok(MidiCsvRecord::EndOfFile),
+            "Text_t" => text(TextKind::Text),
+            "Copyright_t" => text(TextKind::Copyright),
+            "Title_t" => text(TextKind::Title),
+            "Instrument_name_t" => text(TextKind::InstrumentName),
+            "Lyric_t" => text(TextKind::Lyric),
+            "Marker_t" => text(TextKind::Marker),
+            "Cue_point_t" => text(TextKind::CuePoint),
+            "Sequence_number" => Ok(MidiCsvRecord::SequenceNumber(self.number(3, "sequence number")?)),
+            "MIDI_port" => Ok(MidiCsvRecord::MidiPort(self.number(3, "port")?)),
+            "Channel_prefix" => Ok(MidiCsvRecord::ChannelPrefix(self.bounded(3, "channel", MAX_CHANNEL)? as u8)),
+            "Time_signature" => Ok(MidiCsvRecord::TimeSignature {
+                numerator: self.number(3, "numerator")?,
+                denominator_power: self.number(4, "denominator")?,
+                click: self.number(5, "click")?,
+                notes_per_quarter: self.number(6, "notes per quarter")?,
+            }),
+            "Key_signature" => Ok(MidiCsvRecord::KeySignature {
+                sharps: self.bounded_signed(3, "key", 7)?,
+                minor: match self.word(4, "mode")?.to_ascii_lowercase().as_str() {
+                    "major" => false,
+                    "minor" => true,
+                    mode => return Err(LineError::out_of_range(format!("mode {:?}", mode))),
+                },
+            }),
+            "Tempo" => Ok(MidiCsvRecord::Tempo(self.bounded(3, "tempo", 0xff_ffff)?)),
+            "SMPTE_offset" => Ok(MidiCsvRecord::SmpteOffset {
+                hour: self.number(3, "hour")?,
+                minute: self.number(4, "minute")?,
+                second: self.number(5, "second")?,
+                frame: self.number(6, "frame")?,
+                fractional_frame: self.number(7, "fractional frame")?,
+            }),
+            "Sequencer_specific" => Ok(MidiCsvRecord::SequencerSpecific(self.data(3)?)),
+            "Unknown_meta_event" => Ok(MidiCsvRecord::UnknownMetaEvent {
+                meta_type: self.number(3, "meta event type")?,
+                data: self.data(4)?,
+            }),
+            "Note_on_c" => Ok(MidiCsvRecord::NoteOn {
+                channel: self.channel()?,
+                note: self.data_value(4, "note")?,
+                velocity: self.data_value(5, "velocity")?,
+            }),
+            "Note_off_c" => Ok(MidiCsvRecord::NoteOff {
+                channel: self.channel()?,
+                note: self.data_value(4, "note")?,
+                velocity: self.data_value(5, "velocity")?,
+            }),
+            "Pitch_bend_c" => Ok(MidiCsvRecord::PitchBend {
+                channel: self.channel()?,
+                value: self.bounded(4, "pitch bend", MAX_PITCH_BEND)? as u16,
+            }),
+            "Control_c" => Ok(MidiCsvRecord::ControlChange {
+                channel: self.channel()?,
+                controller: self.data_value(4, "controller")?,
+                value: self.data_value(5, "value")?,
+            }),
+            "Program_c" => Ok(MidiCsvRecord::ProgramChange {
+                channel: self.channel()?,
+                program: self.data_value(4, "program")?,
+            }),
+            "Channel_aftertouch_c" => Ok(MidiCsvRecord::ChannelAftertouch {
+                channel: self.channel()?,
+                value: self.data_value(4, "value")?,
+            }),
+            "Poly_aftertouch_c" => Ok(MidiCsvRecord::PolyAftertouch {
+                channel: self.channel()?,
+                note: self.data_value(4, "note")?,
+                value: self.data_value(5, "value")?,
+            }),
+            "System_exclusive" => Ok(MidiCsvRecord::SystemExclusive(self.data(3)?)),
+            "System_exclusive_packet" => Ok(MidiCsvRecord::SystemExclusivePacket(self.data(3)?)),
+            "" => Err(LineError::new(LineErrorKind::MissingFields, "line has no record type".to_string())),
+            record_type => Err(LineError::new(LineErrorKind::UnknownRecord, format!("unknown record type {:?}", record_type))),
+        }
+    }
+
+    fn plain(&self, index: usize, name: &str) -> Result<&str, LineError> {
+        match self.fields.get(index) {
+            Some(Field::Plain(text)) => Ok(text),
+            Some(Field::Quoted(_)) => Err(LineError::new(
+                LineErrorKind::MalformedNumber,
+                format!("{} of a {} record is quoted", name, self.record_type()),
+            )),
+            None => Err(LineError::new(
+                LineErrorKind::MissingFields,
+                format!("{} record has no {} field", self.record_type(), name),
+            )),
+        }
+    }
+
+    /// A keyword field, midicsv quotes some of them and hand-written files often don't
+    fn word(&self, index: usize, name: &str) -> Result<&str, LineError> {
+        match self.fields.get(index) {
+            Some(Field::Plain(text) | Field::Quoted(text)) => Ok(text),
+            None => Err(LineError::new(
+                LineErrorKind::MissingFields,
+                format!("{} record has no {} field", self.record_type(), name),
+            )),
+        }
+    }
+
+    /// Parses field `index`, `name` describes it in errors
+    fn number<T: FromStr>(&self, index: usize, name: &str) -> Result<T, LineError> {
+        let text = self.plain(index, name)?;
+        text.parse().map_err(|_| LineError::new(
+            LineErrorKind::MalformedNumber,
+            format!("{} {:?} of a {} record is not a number", name, text, self.record_type()),
+        ))
+    }
+
+    fn bounded(&self, index: usize, name: &str, max: u32) -> Result<u32, LineError> {
+        let value: i64 = self.number(index, name)?;
+        if !(0..=max as i64).contains(&value) {
+            return Err(LineError::out_of_range(format!("{} {}", name, value)));
+        }
+        Ok(value as u32)
+    }
+
+    fn bounded_signed(&self, index: usize, name: &str, limit: i8) -> Result<i8, LineError> {
+        let value: i64 = self.number(index, name)?;
+        if !(-limit as i64..=limit as i64).contains(&value) {
+            return Err(LineError::out_of_range(format!("{} {}", name, value)));
+        }
+        Ok(value as i8)
+    }
+
+    fn channel(&self) -> Result<u8, LineError> {
+        Ok(self.bounded(3, "channel", MAX_CHANNEL)? as u8)
+    }
+
+    fn data_value(&self, index: usize, name: &str) -> Result<u8, LineError> {
+        Ok(self.bounded(index, name, MAX_DATA_VALUE)? as u8)
+    }
+
+    fn text(&self, index: usize) -> Result<String, LineError> {
+        match self.fields.get(index) {
+            Some(Field::Quoted(text)) => Ok(text.clone()),
+            Some(Field::Plain(_)) => Err(invalid_text(format!("{} text is not quoted", self.record_type()))),
+            None => Err(LineError::new(
+                LineErrorKind::MissingFields,
+                format!("{} record has no text", self.record_type()),
+            )),
+        }
+    }
+
+    /// Reads a byte count at `index` followed by that many bytes
+    fn data(&self, index: usize) -> Result<Vec<u8>, LineError> {
+        let length: usize = self.number(index, "length")?;
+        let data = (index + 1..self.fields.len())
+            .map(|byte| Ok(self.bounded(byte, "data byte", 255)? as u8))
+            .collect::<Result<Vec<u8>, LineError>>()?;
+
+        if data.len() != length {
+            return Err(LineError::new(
+                LineErrorKind::MissingFields,
+                format!("{} record declares {} data bytes but has {}", self.record_type(), length, data.len()),
+            ));
+        }
+        Ok(data)
+    }
+}
+
+fn invalid_text(message: String) -> LineError {
+    LineError::new(LineErrorKind::InvalidText, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MidiProcessor, OutputTarget};
+
+    fn record(line: &str) -> MidiCsvRecord {
+        parse_line(line.as_bytes()).unwrap().unwrap().record
+    }
+
+    fn error(line: &str) -> LineError {
+        parse_line(line.as_bytes()).unwrap_err()
+    }
+
+    #[test]
+    fn quoted_fields_keep_commas_and_escapes() {
+        assert_eq!(
+            record(r#"2, 0, Title_t, "Piano, left hand""#),
+            MidiCsvRecord::Text { kind: TextKind::Title, text: "Piano, left hand".to_string() }
+        );
+        assert_eq!(
+            record(r#"2, 0, Text_t, "say ""hi"", \\ \101""#),
+            MidiCsvRecord::Text { kind: TextKind::Text, text: r#"say "hi", \ A"#.to_string() }
+        );
+        assert_eq!(error(r#"2, 0, Text_t, "no end, here"#).kind, LineErrorKind::InvalidText);
+        assert_eq!(error(r#"2, 0, Text_t, "a" b"#).kind, LineErrorKind::InvalidText);
+        assert_eq!(error("2, 0, Text_t, unquoted").kind, LineErrorKind::InvalidText);
+    }
+
+    #[test]
+    fn blank_lines_and_comments_are_not_records() {
+        assert_eq!(parse_line(b"").unwrap(), None);
+        assert_eq!(parse_line(b"   ").unwrap(), None);
+        assert_eq!(parse_line(b"# 1, 0, Start_track").unwrap(), None);
+        assert_eq!(parse_line(b"; comment").unwrap(), None);
+    }
+
+    #[test]
+    fn system_exclusive_and_sequencer_specific_data() {
+        assert_eq!(record("1, 0, System_exclusive, 3, 65, 16, 247"), MidiCsvRecord::SystemExclusive(vec![65, 16, 247]));
+        assert_eq!(record("1, 0, System_exclusive_packet, 1, 247"), MidiCsvRecord::SystemExclusivePacket(vec![247]));
+        assert_eq!(record("1, 0, Sequencer_specific, 2, 0, 65"), MidiCsvRecord::SequencerSpecific(vec![0, 65]));
+        assert_eq!(record("1, 0, Sequencer_specific, 0"), MidiCsvRecord::SequencerSpecific(Vec::new()));
+
+        assert_eq!(error("1, 0, System_exclusive, 3, 65, 16").kind, LineErrorKind::MissingFields);
+        assert_eq!(error("1, 0, Sequencer_specific, 1, 256").kind, LineErrorKind::OutOfRange);
+        assert_eq!(error("1, 0, Sequencer_specific, two, 1, 2").kind, LineErrorKind::MalformedNumber);
+    }
+
+    #[test]
+    fn key_signatures() {
+        assert_eq!(record("1, 0, Key_signature, 0, \"major\""), MidiCsvRecord::KeySignature { sharps: 0, minor: false });
+        assert_eq!(record("1, 0, Key_signature, -3, minor"), MidiCsvRecord::KeySignature { sharps: -3, minor: true });
+        assert_eq!(record("1, 0, Key_signature, 7, Major"), MidiCsvRecord::KeySignature { sharps: 7, minor: false });
+
+        assert_eq!(error("1, 0, Key_signature, 8, major").kind, LineErrorKind::OutOfRange);
+        assert_eq!(error("1, 0, Key_signature, 0, dorian").kind, LineErrorKind::OutOfRange);
+        assert_eq!(error("1, 0, Key_signature, 0").kind, LineErrorKind::MissingFields);
+    }
+
+    #[test]
+    fn notes_and_unknown_records() {
+        let line = parse_line(b"2, 960, Note_on_c, 0, 60, 100").unwrap().unwrap();
+        assert_eq!((line.track, line.tick), (2, 960));
+        assert_eq!(line.record, MidiCsvRecord::NoteOn { channel: 0, note: 60, velocity: 100 });
+
+        assert_eq!(error("2, 0, Note_on_c, 16, 60, 100").kind, LineErrorKind::OutOfRange);
+        assert_eq!(error("2, x, Note_on_c, 0, 60, 100").kind, LineErrorKind::MalformedNumber);
+        assert_eq!(error("2, 0, Note_on_c, 0, 60").kind, LineErrorKind::MissingFields);
+        assert_eq!(error("2, 0, Vibrato_c, 0").kind, LineErrorKind::UnknownRecord);
+    }
+
+    #[test]
+    fn malformed_lines_are_skipped_with_their_line_number() {
+        let directory = std::env::temp_dir().join(format!("midicsv_skip_test_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let input = directory.join("skip.csv");
+        std::fs::write(
+            &input,
+            "0, 0, Header, 1, 2, 480\n\
+             1, 0, Start_track\n\
+             1, 0, Tempo, fast\n\
+             2, 0, Note_on_c, 0, 60, 100\n\
+             2, 0, Title_t, \"unterminated\n\
+             2, 480, Note_off_c, 0, 60, 0\n\
+             0, 0, End_of_file\n",
+        )
+        .unwrap();
+
+        let output = OutputTarget::File(directory.join("skip.cary"));
+        let report = MidiProcessor::default().process_file(&input, &output);
+        let written = std::fs::read_to_string(directory.join("skip.cary"));
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let report = report.unwrap();
+        let skipped: Vec<(usize, LineErrorKind)> = report.skipped.iter().map(|error| (error.line, error.kind)).collect();
+        assert_eq!(skipped, vec![(3, LineErrorKind::MalformedNumber), (5, LineErrorKind::InvalidText)]);
+        // The lines around them still count, middle C is written as 'G'
+        let written = written.unwrap();
+        assert!(written.rsplit("%end\n").next().unwrap().contains('G'));
+    }
+}
@@ -0,0 +1,78 @@
+use std::io::{self, Write};
+use smai_config::format::{bin_of_velocity, BAR_MARKER, MAX_BAR_FRAMES, MAX_DURATION, TEMPO_RANGE, TEMPO_STEP};
+use crate::events::{write_tokens, DEFAULT_VELOCITY};
+use crate::{AugmentedPiece, NoteState, MIDI_PITCHES};
+
+/// Writes a piece REMI style: `bar` starts every bar and `meter<frames>` gives its length when that changes,
+/// `pos<frame>` places the notes and tempo changes that follow within the bar, each note is `pitch<p>` then
+/// `dur<frames>`, with `v<bin>` before it when the velocity bin changes and `tempo<bpm>` marking tempo changes.
+/// Pitches are MIDI note numbers after transposition. Returns the bytes written.
+pub(crate) fn write_remi(
+    output_file: &mut impl Write,
+    piece: &AugmentedPiece,
+    transposition: i32,
+    is_written: impl Fn(usize) -> bool,
+) -> io::Result<usize> {
+    let written: Vec<usize> = (0..MIDI_PITCHES).filter(|pitch| is_written(*pitch)).collect();
+    let frame_count = piece.frames.len();
+
+    // A cropped piece may start mid-bar, that part counts as a bar of its own
+    let marked: Vec<usize> = (0..frame_count).filter(|frame| *frame == 0 || piece.markers[*frame] == Some(BAR_MARKER)).collect();
+    let mut bar_starts = Vec::new();
+    for (index, &start) in marked.iter().enumerate() {
+        let end = marked.get(index + 1).copied().unwrap_or(frame_count);
+        bar_starts.extend((start..end).step_by(MAX_BAR_FRAMES));
+    }
+
+    let mut tokens = Vec::new();
+    let mut meter = None;
+    let mut velocity_bin = None;
+    for (index, &start) in bar_starts.iter().enumerate() {
+        tokens.push("bar".to_string());
+        let end = bar_starts.get(index + 1).copied();
+        // Only the start of the next bar needs the length, the last one goes without
+        if let Some(length) = end.map(|end| end - start).filter(|length| meter != Some(*length)) {
+            tokens.push(format!("meter{}", length));
+            meter = Some(length);
+        }
+
+        for frame in start..end.unwrap_or(frame_count) {
+            let mut events = Vec::new();
+            if let Some(tempo) = piece.tempos[frame] {
+                events.push(format!("tempo{}", tempo_bpm(tempo)));
+            }
+            for &pitch in &written {
+                let velocity = match piece.frames[frame][pitch] {
+                    NoteState::On(velocity) => velocity,
+                    // Sounding since before a crop
+                    NoteState::Sustained if frame == 0 => DEFAULT_VELOCITY,
+                    _ => continue,
+                };
+                let bin = bin_of_velocity(velocity);
+                if velocity_bin != Some(bin) {
+                    events.push(format!("v{}", bin));
+                    velocity_bin = Some(bin);
+                }
+                let held = piece.frames[frame + 1..]
+                    .iter()
+                    .take_while(|notes| notes[pitch] == NoteState::Sustained)
+                    .count();
+                events.push(format!("pitch{}", pitch as i32 + transposition));
+                events.push(format!("dur{}", (held + 1).min(MAX_DURATION)));
+            }
+
+            if !events.is_empty() {
+                tokens.push(format!("pos{}", frame - start));
+                tokens.extend(events);
+            }
+        }
+    }
+    write_tokens(output_file, tokens)
+}
+
+/// Microseconds per quarter note as beats per minute, rounded to the closest tempo token
+fn tempo_bpm(tempo: u32) -> u32 {
+    let bpm = 60_000_000.0 / tempo.max(1) as f32;
+    let steps = ((bpm - TEMPO_RANGE.0 as f32) / TEMPO_STEP as f32).round().max(0.0) as u32;
+    (TEMPO_RANGE.0 + steps * TEMPO_STEP).min(TEMPO_RANGE.1)
+}
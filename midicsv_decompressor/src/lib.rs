@@ -0,0 +1,948 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, read_dir};
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use clap::Args;
+use midicsv_compressor::midicsv::{parse_line, MidiCsvRecord};
+use serde::Deserialize;
+use smai_config::format::{repeat_power, velocity_of_bin, BAR_MARKER, BEAT_MARKER, CARY_FORMAT_VERSION};
+use smai_config::{ConfigArgs, Section};
+
+// Constants
+pub const INPUT_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../data/input/cary/");
+pub const OUTPUT_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../data/output/midicsv/");
+const TIME_QUANTUM: u32 = 40;  // Same as compressor's quantization, used when a file has no header
+const DIVISION: u32 = 384;
+const TEMPO: u32 = 500_000;  // Microseconds per quarter note when a file has no tempo
+const PITCH_OFFSET: i32 = 21;  // MIDI note number of '!' when a file has no header
+const PITCH_RANGE: usize = 94;  // One pitch per printable ASCII character
+const NOTE_VELOCITY: u8 = 127;  // The .cary format has no velocities
+
+/// Metadata block written by the compressor at the start of a .cary file
+#[derive(Default)]
+struct CaryHeader {
+    entries: Vec<(String, String)>,  // Keys in file order, some may repeat
+}
+
+impl CaryHeader {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(entry_key, _)| entry_key == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(entry_key, _)| entry_key == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn parse<T: FromStr>(&self, key: &str) -> Option<T> {
+        self.get(key).and_then(|value| value.parse().ok())
+    }
+}
+
+/// The [decompressor] section: the timing and pitch range of .cary files without a header, and how long
+/// a held note may last before it is closed
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DecompressorConfig {
+    pub ticks_per_frame: f64,
+    pub division: u32,
+    pub tempo: u32,
+    pub pitch_offset: i32,
+    pub max_note_length: Option<usize>,
+}
+
+impl Default for DecompressorConfig {
+    fn default() -> Self {
+        DecompressorConfig {
+            ticks_per_frame: TIME_QUANTUM as f64,
+            division: DIVISION,
+            tempo: TEMPO,
+            pitch_offset: PITCH_OFFSET,
+            max_note_length: None,
+        }
+    }
+}
+
+impl Section for DecompressorConfig {
+    const NAME: &'static str = "decompressor";
+
+    fn validate(&self) -> Result<(), String> {
+        if self.ticks_per_frame <= 0.0 {
+            return Err("ticks_per_frame must be positive".to_string());
+        }
+        if self.division == 0 || self.division > 0x7fff {
+            return Err("division must be between 1 and 32767".to_string());
+        }
+        if self.tempo == 0 || self.tempo > 0xff_ffff {
+            return Err("tempo must be between 1 and 16777215".to_string());
+        }
+        if self.pitch_offset < 0 || self.pitch_offset + PITCH_RANGE as i32 > 128 {
+            return Err(format!("pitch_offset must leave room for {} MIDI pitches", PITCH_RANGE));
+        }
+        if self.max_note_length == Some(0) {
+            return Err("max_note_length must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Args)]
+pub struct DecompressArgs {
+    /// .cary file or directory of files to decompress, `-` for stdin
+    #[arg(default_value = INPUT_DIR)]
+    pub input: PathBuf,
+    /// Directory or file for the midicsv output, `-` for stdout
+    #[arg(default_value = OUTPUT_DIR)]
+    pub output: PathBuf,
+    #[command(flatten)]
+    pub timing_overrides: TimingOverrides,
+    /// Longest a note may sound, in frames, before it is closed
+    #[arg(long)]
+    pub max_note_length: Option<usize>,
+    #[command(flatten)]
+    pub config: ConfigArgs,
+}
+
+/// Command line settings that replace the timing a .cary file carries
+#[derive(Args, Clone, Default)]
+pub struct TimingOverrides {
+    /// Tempo in microseconds per quarter note, replacing every tempo change in the file
+    #[arg(long)]
+    pub tempo: Option<u32>,
+    /// Time signature such as 3/4, replacing every meter change in the file
+    #[arg(long, value_parser = parse_time_signature)]
+    pub time_signature: Option<TimeSignature>,
+    /// Ticks per quarter note of the reconstructed file
+    #[arg(long)]
+    pub division: Option<u32>,
+}
+
+#[derive(Clone, Copy)]
+pub struct TimeSignature {
+    pub numerator: u32,
+    pub denominator: u32,
+}
+
+fn parse_time_signature(value: &str) -> Result<TimeSignature, String> {
+    let (numerator, denominator) = value.split_once('/').ok_or("expected NUMERATOR/DENOMINATOR")?;
+    let numerator = numerator.trim().parse::<u32>().map_err(|e| e.to_string())?;
+    let denominator = denominator.trim().parse::<u32>().map_err(|e| e.to_string())?;
+    if numerator == 0 || !denominator.is_power_of_two() {
+        return Err("the denominator must be a power of two".to_string());
+    }
+    Ok(TimeSignature { numerator, denominator })
+}
+
+/// What a loaded .cary file contains, or a whole corpus once summaries are merged
+#[derive(Default)]
+pub struct CarySummary {
+    pub frames: usize,
+    pub sounding_frames: usize,  // Frames with at least one note
+    pub onsets: usize,
+    pub lowest_pitch: Option<i32>,  // MIDI note numbers
+    pub highest_pitch: Option<i32>,
+    pub max_polyphony: usize,
+    pub pitch_onsets: Vec<usize>,      // Onsets per MIDI note number
+    pub polyphony_frames: Vec<usize>,  // Frames per number of sounding notes
+    pub characters: HashSet<char>,     // Pitch characters used, out of the PITCH_RANGE printable ones
+    pub chords: HashSet<u128>,         // Distinct frame contents, one bit per pitch character
+}
+
+impl CarySummary {
+    pub fn merge(&mut self, other: &CarySummary) {
+        self.frames += other.frames;
+        self.sounding_frames += other.sounding_frames;
+        self.onsets += other.onsets;
+        self.lowest_pitch = [self.lowest_pitch, other.lowest_pitch].into_iter().flatten().min();
+        self.highest_pitch = self.highest_pitch.max(other.highest_pitch);
+        self.max_polyphony = self.max_polyphony.max(other.max_polyphony);
+        add_counts(&mut self.pitch_onsets, &other.pitch_onsets);
+        add_counts(&mut self.polyphony_frames, &other.polyphony_frames);
+        self.characters.extend(&other.characters);
+        self.chords.extend(&other.chords);
+    }
+
+    /// Size of the pitch character vocabulary the coverage is measured against
+    pub fn vocabulary_size() -> usize {
+        PITCH_RANGE
+    }
+}
+
+fn add_counts(counts: &mut Vec<usize>, other: &[usize]) {
+    if counts.len() < other.len() {
+        counts.resize(other.len(), 0);
+    }
+    for (count, other) in counts.iter_mut().zip(other) {
+        *count += other;
+    }
+}
+
+pub struct MidiDecompressor {
+    note_matrix: Vec<[bool; PITCH_RANGE]>,  // Time × Pitch matrix, grown as frames are read
+    onsets: HashMap<(usize, usize), u8>,    // Velocity by time and pitch, for files that mark where notes start
+    current_time_step: usize,
+    header: CaryHeader,
+    ticks_per_frame: f64,
+    division: u32,
+    pitch_offset: i32,
+    tempos: Vec<(f64, u32)>,  // Output tick and microseconds per quarter note
+    time_signatures: Vec<(f64, TimeSignature)>,
+    timing_overrides: TimingOverrides,
+    config: DecompressorConfig,
+}
+
+impl MidiDecompressor {
+    pub fn new(config: DecompressorConfig, timing_overrides: TimingOverrides) -> Self {
+        MidiDecompressor {
+            note_matrix: Vec::new(),
+            onsets: HashMap::new(),
+            current_time_step: 0,
+            header: CaryHeader::default(),
+            ticks_per_frame: config.ticks_per_frame,
+            division: config.division,
+            pitch_offset: config.pitch_offset,
+            tempos: Vec::new(),
+            time_signatures: Vec::new(),
+            timing_overrides,
+            config,
+        }
+    }
+
+    pub fn load_compressed_file(&mut self, file_path: &Path) -> std::io::Result<()> {
+        if file_path == Path::new("-") {
+            self.load_compressed(io::stdin().lock())
+        } else {
+            self.load_compressed(BufReader::new(File::open(file_path)?))
+        }
+    }
+
+    pub fn load_compressed(&mut self, reader: impl BufRead) -> std::io::Result<()> {
+        self.reset_state();
+
+        let mut lines = reader.lines().peekable();
+
+        if let Some(Ok(first_line)) = lines.peek() {
+            if first_line.starts_with("%cary ") {
+                self.load_header(&mut lines)?;
+            }
+        }
+        self.apply_timing_overrides();
+
+        match self.header.get("encoding") {
+            Some("events") => return self.load_events(lines),
+            Some("remi") => return self.load_remi(lines),
+            _ => (),
+        }
+
+        for line in lines {
+            let line = line?;
+            self.process_compressed_line(&line);
+        }
+
+        // Finalize the last time step
+        self.current_time_step += 1;
+        
+        Ok(())
+    }
+
+    /// Reads the `on<pitch>`, `off<pitch>`, `t<frames>` and `v<bin>` tokens of the events encoding.
+    /// Pitches are MIDI note numbers, tokens and pitches the format can't hold are skipped.
+    fn load_events(&mut self, lines: impl Iterator<Item = std::io::Result<String>>) -> std::io::Result<()> {
+        let mut started_at: [Option<usize>; PITCH_RANGE] = [None; PITCH_RANGE];
+        let mut velocity = NOTE_VELOCITY;
+
+        for line in lines {
+            let line = line?;
+            for token in line.split_whitespace() {
+                // Meter markers carry no value, and no notes
+                let (kind, Some(value)) = Self::split_token(token) else {
+                    continue;
+                };
+                let pitch = self.pitch_index(value);
+
+                match (kind, pitch) {
+                    ("t", _) => self.current_time_step += value as usize,
+                    ("v", _) => velocity = velocity_of_bin(value),
+                    ("on", Some(pitch)) => {
+                        // Struck again without a release
+                        if let Some(start) = started_at[pitch] {
+                            self.hold_note(pitch, start, self.current_time_step);
+                        }
+                        started_at[pitch] = Some(self.current_time_step);
+                        self.onsets.insert((self.current_time_step, pitch), velocity);
+                    }
+                    ("off", Some(pitch)) => {
+                        if let Some(start) = started_at[pitch].take() {
+                            self.hold_note(pitch, start, self.current_time_step);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        // Notes never released last until the final event
+        for (pitch, start) in started_at.into_iter().enumerate() {
+            if let Some(start) = start {
+                self.hold_note(pitch, start, self.current_time_step);
+            }
+        }
+        self.current_time_step = self.current_time_step.max(self.note_matrix.len());
+        Ok(())
+    }
+
+    /// Reads the tokens of the REMI encoding: `bar`, `meter<frames>` for the length of the bars that follow,
+    /// `pos<frame>` within the bar, `pitch<p>` and `dur<frames>` for each note, `v<bin>` and `tempo<bpm>`.
+    /// Tempo tokens are only used when neither the header nor the command line gives the tempo.
+    fn load_remi(&mut self, lines: impl Iterator<Item = std::io::Result<String>>) -> std::io::Result<()> {
+        let mut bar_start = None;
+        let mut meter = 0;
+        let mut time = 0;
+        let mut velocity = NOTE_VELOCITY;
+        let mut pitch = None;
+        let mut tempos = Vec::new();  // Frame and microseconds per quarter note
+
+        for line in lines {
+            let line = line?;
+            for token in line.split_whitespace() {
+                match Self::split_token(token) {
+                    ("bar", None) => {
+                        let start = bar_start.map_or(0, |start| start + meter);
+                        bar_start = Some(start);
+                        time = start;
+                    }
+                    ("meter", Some(length)) => meter = length as usize,
+                    ("pos", Some(position)) => time = bar_start.unwrap_or(0) + position as usize,
+                    ("tempo", Some(bpm)) if bpm > 0 => tempos.push((time, 60_000_000 / bpm)),
+                    ("v", Some(bin)) => velocity = velocity_of_bin(bin),
+                    ("pitch", Some(value)) => pitch = self.pitch_index(value),
+                    // Skipped with a pitch out of range
+                    ("dur", Some(duration)) => {
+                        if let Some(pitch) = pitch.take() {
+                            self.hold_note(pitch, time, time + duration as usize);
+                            self.onsets.insert((time, pitch), velocity);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+        self.current_time_step = self.note_matrix.len();
+
+        if !tempos.is_empty() && self.header.get("tempo").is_none() && self.timing_overrides.tempo.is_none() {
+            // Replaces the default tempo rather than following it at the same tick
+            if tempos[0].0 == 0 {
+                self.tempos.clear();
+            }
+            let ticks_per_frame = self.ticks_per_frame;
+            self.tempos.extend(tempos.into_iter().map(|(frame, tempo)| (frame as f64 * ticks_per_frame, tempo)));
+            self.tempos.sort_by(|a, b| a.0.total_cmp(&b.0));
+        }
+        Ok(())
+    }
+
+    /// Splits a token such as `on60` into its word and number, None for tokens without one
+    fn split_token(token: &str) -> (&str, Option<u32>) {
+        let (kind, value) = token.split_at(token.find(|c: char| c.is_ascii_digit()).unwrap_or(token.len()));
+        (kind, value.parse().ok())
+    }
+
+    /// Index in the note matrix of a MIDI note number, None for pitches the matrix has no room for
+    fn pitch_index(&self, pitch: u32) -> Option<usize> {
+        usize::try_from(pitch as i64 - self.pitch_offset as i64).ok().filter(|pitch| *pitch < PITCH_RANGE)
+    }
+
+    /// Marks a pitch as sounding from `start` until `end`, at least one frame long
+    fn hold_note(&mut self, pitch: usize, start: usize, end: usize) {
+        let end = end.max(start + 1);
+        if self.note_matrix.len() < end {
+            self.note_matrix.resize(end, [false; PITCH_RANGE]);
+        }
+        for notes in &mut self.note_matrix[start..end] {
+            notes[pitch] = true;
+        }
+    }
+
+    fn load_header(&mut self, lines: &mut impl Iterator<Item = std::io::Result<String>>) -> std::io::Result<()> {
+        for line in lines.by_ref() {
+            let line = line?;
+            if line == "%end" {
+                break;
+            }
+            let Some(entry) = line.strip_prefix('%') else {
+                return Err(Error::new(ErrorKind::InvalidData, format!("Malformed header line: {}", line)));
+            };
+            let (key, value) = entry.split_once(' ').unwrap_or((entry, ""));
+            self.header.entries.push((key.to_string(), value.to_string()));
+        }
+
+        match self.header.parse::<u32>("cary") {
+            Some(version) if version <= CARY_FORMAT_VERSION => (),
+            _ => return Err(Error::new(ErrorKind::InvalidData, "Unsupported .cary format version")),
+        }
+
+        self.ticks_per_frame = self.header.parse("ticks_per_frame").unwrap_or(self.ticks_per_frame);
+        self.division = self.header.parse::<f64>("division").map_or(self.division, |division| division.round() as u32);
+        self.pitch_offset = self.header.parse("pitch_offset").unwrap_or(self.pitch_offset);
+
+        // Stretched copies last longer, so their meter and tempo changes come later too
+        let stretch = self.header.parse::<f64>("tempo_stretch").unwrap_or(1.0);
+        for tempo in self.header.get_all("tempo") {
+            if let [Ok(tick), Ok(tempo)] = Self::split_fields::<f64, 2>(tempo) {
+                self.tempos.push((tick * stretch, tempo as u32));
+            }
+        }
+        for signature in self.header.get_all("time_signature") {
+            if let [Ok(tick), Ok(numerator), Ok(denominator)] = Self::split_fields::<f64, 3>(signature) {
+                let signature = TimeSignature { numerator: numerator as u32, denominator: denominator as u32 };
+                if signature.numerator > 0 && signature.denominator.is_power_of_two() {
+                    self.time_signatures.push((tick * stretch, signature));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn split_fields<T: FromStr, const N: usize>(value: &str) -> [Result<T, ()>; N] {
+        let mut fields = value.split_whitespace();
+        std::array::from_fn(|_| fields.next().ok_or(()).and_then(|field| field.parse().map_err(|_| ())))
+    }
+
+    fn apply_timing_overrides(&mut self) {
+        let overrides = &self.timing_overrides;
+        if let Some(division) = overrides.division {
+            // Rescale so notes keep their place in the bar
+            let scale = division as f64 / self.division as f64;
+            self.ticks_per_frame *= scale;
+            self.tempos.iter_mut().for_each(|(tick, _)| *tick *= scale);
+            self.time_signatures.iter_mut().for_each(|(tick, _)| *tick *= scale);
+            self.division = division;
+        }
+        if let Some(tempo) = overrides.tempo {
+            self.tempos = vec![(0.0, tempo)];
+        }
+        if let Some(signature) = overrides.time_signature {
+            self.time_signatures = vec![(0.0, signature)];
+        }
+
+        if self.tempos.is_empty() {
+            self.tempos.push((0.0, self.config.tempo));
+        }
+        if self.time_signatures.is_empty() {
+            self.time_signatures.push((0.0, TimeSignature { numerator: 4, denominator: 4 }));
+        }
+        self.tempos.sort_by(|a, b| a.0.total_cmp(&b.0));
+        self.time_signatures.sort_by(|a, b| a.0.total_cmp(&b.0));
+    }
+
+    fn process_compressed_line(&mut self, line: &str) {
+        for c in line.chars() {
+            match c {
+                ' ' => self.current_time_step += 1,
+                // Meter markers carry no notes (bar lines are already split off by BufReader)
+                BAR_MARKER | BEAT_MARKER => (),
+                _ => match repeat_power(c) {
+                    Some(power) => self.repeat_frame(1 << power),
+                    None => self.process_note_char(c),
+                },
+            }
+        }
+    }
+
+    /// Copies the last completed frame, for files written with run-length encoding
+    fn repeat_frame(&mut self, count: usize) {
+        let Some(previous) = self.current_time_step.checked_sub(1) else {
+            return;
+        };
+        let frame = self.note_matrix.get(previous).copied().unwrap_or([false; PITCH_RANGE]);
+        self.note_matrix.resize(self.current_time_step, [false; PITCH_RANGE]);
+        self.note_matrix.extend(std::iter::repeat_n(frame, count));
+        self.current_time_step += count;
+    }
+
+    fn process_note_char(&mut self, c: char) {
+        let pitch = c as i32 - 33;  // Convert ASCII to pitch index
+        if (0..PITCH_RANGE as i32).contains(&pitch) {
+            if self.note_matrix.len() <= self.current_time_step {
+                self.note_matrix.resize(self.current_time_step + 1, [false; PITCH_RANGE]);
+            }
+            self.note_matrix[self.current_time_step][pitch as usize] = true;
+        }
+    }
+
+    pub fn generate_midi_csv(&self) -> std::io::Result<Vec<u8>> {
+        let mut writer = Vec::new();
+
+        self.write_midi_header(&mut writer)?;
+        self.write_note_events(&mut writer)?;
+        self.write_midi_footer(&mut writer)?;
+
+        Ok(writer)
+    }
+
+    fn write_midi_header(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        writeln!(writer, "0, 0, Header, 1, 3, {}", self.division)?;
+        writeln!(writer, "1, 0, Start_track")?;
+        self.write_tempo_map(writer)?;
+
+        // Tempo changes past the last note still belong to the track
+        let last_change = self.tempos.iter().map(|(tick, _)| *tick)
+            .chain(self.time_signatures.iter().map(|(tick, _)| *tick))
+            .fold(0.0, f64::max);
+        writeln!(writer, "1, {}, End_track", self.tick(self.current_time_step).max(last_change.round() as u64))?;
+        writeln!(writer, "2, 0, Start_track")?;
+        writeln!(writer, r#"2, 0, Text_t, "Decompressed MIDI""#)?;
+        writeln!(writer, r#"2, 0, Title_t, "Main Track""#)?;
+        
+        Ok(())
+    }
+
+    fn write_tempo_map(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        let mut signatures = self.time_signatures.iter().peekable();
+        let mut tempos = self.tempos.iter().peekable();
+
+        // Merge both lists so the track stays in time order, meter first on ties
+        loop {
+            let signature_first = match (signatures.peek(), tempos.peek()) {
+                (Some(signature), Some(tempo)) => signature.0 <= tempo.0,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+            if signature_first {
+                let (tick, signature) = signatures.next().unwrap();
+                writeln!(
+                    writer,
+                    "1, {}, Time_signature, {}, {}, 24, 8",
+                    tick.round() as u64,
+                    signature.numerator,
+                    signature.denominator.trailing_zeros()  // Stored as a power of two
+                )?;
+            } else {
+                let (tick, tempo) = tempos.next().unwrap();
+                writeln!(writer, "1, {}, Tempo, {}", tick.round() as u64, tempo)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_note_events(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        let mut started_at: [Option<usize>; PITCH_RANGE] = [None; PITCH_RANGE];
+        let mut cut_short = [false; PITCH_RANGE];  // Closed by max_note_length, waiting for the pitch to stop
+
+        // The extra frame past the end closes every note still held by the last one
+        for time in 0..=self.current_time_step {
+            for pitch in 0..PITCH_RANGE {
+                let sounding = time < self.current_time_step && self.is_sounding(time, pitch);
+                let onset = self.onsets.get(&(time, pitch)).copied();
+                let too_long = |start: usize| self.config.max_note_length.is_some_and(|max| time - start >= max);
+
+                match started_at[pitch] {
+                    None if sounding && (!cut_short[pitch] || onset.is_some()) => {
+                        self.write_note_on(writer, time, pitch, onset.unwrap_or(NOTE_VELOCITY))?;
+                        started_at[pitch] = Some(time);
+                        cut_short[pitch] = false;
+                    }
+                    Some(_) if !sounding => {
+                        self.write_note_off(writer, time, pitch)?;
+                        started_at[pitch] = None;
+                    }
+                    // Struck again while still held
+                    Some(start) if start < time && onset.is_some() => {
+                        self.write_note_off(writer, time, pitch)?;
+                        self.write_note_on(writer, time, pitch, onset.unwrap_or(NOTE_VELOCITY))?;
+                        started_at[pitch] = Some(time);
+                    }
+                    Some(start) if too_long(start) => {
+                        self.write_note_off(writer, time, pitch)?;
+                        started_at[pitch] = None;
+                        cut_short[pitch] = true;
+                    }
+                    _ => (),
+                }
+                if !sounding {
+                    cut_short[pitch] = false;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Header entries in file order, empty for files without a header
+    pub fn header_entries(&self) -> &[(String, String)] {
+        &self.header.entries
+    }
+
+    pub fn summary(&self) -> CarySummary {
+        let mut summary = CarySummary { frames: self.current_time_step, ..CarySummary::default() };
+        // Frames after the last note are silent but still count
+        summary.polyphony_frames = vec![self.current_time_step.saturating_sub(self.note_matrix.len())];
+
+        for (time, notes) in self.note_matrix.iter().enumerate() {
+            let polyphony = notes.iter().filter(|sounding| **sounding).count();
+            summary.sounding_frames += (polyphony > 0) as usize;
+            summary.max_polyphony = summary.max_polyphony.max(polyphony);
+            if summary.polyphony_frames.len() <= polyphony {
+                summary.polyphony_frames.resize(polyphony + 1, 0);
+            }
+            summary.polyphony_frames[polyphony] += 1;
+
+            let mut chord = 0u128;
+            for pitch in (0..PITCH_RANGE).filter(|pitch| notes[*pitch]) {
+                let midi_pitch = pitch as i32 + self.pitch_offset;
+                summary.lowest_pitch = Some(summary.lowest_pitch.map_or(midi_pitch, |lowest| lowest.min(midi_pitch)));
+                summary.highest_pitch = Some(summary.highest_pitch.map_or(midi_pitch, |highest| highest.max(midi_pitch)));
+                summary.characters.insert((b'!' + pitch as u8) as char);
+                chord |= 1 << pitch;
+                if time == 0 || !self.is_sounding(time - 1, pitch) || self.onsets.contains_key(&(time, pitch)) {
+                    summary.onsets += 1;
+                    if let Ok(midi_pitch) = usize::try_from(midi_pitch) {
+                        if summary.pitch_onsets.len() <= midi_pitch {
+                            summary.pitch_onsets.resize(midi_pitch + 1, 0);
+                        }
+                        summary.pitch_onsets[midi_pitch] += 1;
+                    }
+                }
+            }
+            if chord != 0 {
+                summary.chords.insert(chord);
+            }
+        }
+        summary
+    }
+
+    /// Frames after the last note were never stored and are silent
+    fn is_sounding(&self, time: usize, pitch: usize) -> bool {
+        self.note_matrix.get(time).is_some_and(|notes| notes[pitch])
+    }
+
+    fn write_note_on(&self, writer: &mut impl Write, time: usize, pitch: usize, velocity: u8) -> std::io::Result<()> {
+        writeln!(
+            writer,
+            "2, {}, Note_on_c, 1, {}, {}",
+            self.tick(time),
+            pitch as i32 + self.pitch_offset,  // Convert to MIDI note number
+            velocity
+        )
+    }
+
+    fn write_note_off(&self, writer: &mut impl Write, time: usize, pitch: usize) -> std::io::Result<()> {
+        writeln!(
+            writer,
+            "2, {}, Note_off_c, 1, {}, 0",
+            self.tick(time),
+            pitch as i32 + self.pitch_offset  // Convert to MIDI note number
+        )
+    }
+
+    fn write_midi_footer(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        writeln!(writer, "2, {}, End_track", self.tick(self.current_time_step))?;
+        writeln!(writer, "0, 0, End_of_file")?;
+        Ok(())
+    }
+
+    fn tick(&self, time: usize) -> u64 {
+        (time as f64 * self.ticks_per_frame).round() as u64
+    }
+
+    fn reset_state(&mut self) {
+        self.note_matrix.clear();
+        self.onsets.clear();
+        self.current_time_step = 0;
+        self.header = CaryHeader::default();
+        self.ticks_per_frame = self.config.ticks_per_frame;
+        self.division = self.config.division;
+        self.pitch_offset = self.config.pitch_offset;
+        self.tempos.clear();
+        self.time_signatures.clear();
+    }
+}
+
+/// Checks that every line of a midicsv file parses and every Note_on_c is closed before its track ends,
+/// or before the file does for a track without End_track
+pub fn validate_midi_csv(reader: impl BufRead) -> std::io::Result<()> {
+    let mut open_notes: HashMap<(u8, u8), Vec<usize>> = HashMap::new();  // Channel and note to line numbers
+
+    for (index, line) in reader.split(b'\n').enumerate() {
+        let line_number = index + 1;
+        let parsed = parse_line(&line?)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("line {}: {}", line_number, e.message)))?;
+        let Some(parsed) = parsed else {
+            continue;
+        };
+
+        match parsed.record {
+            MidiCsvRecord::StartTrack | MidiCsvRecord::EndTrack => {
+                check_notes_closed(&open_notes)?;
+                open_notes.clear();
+            }
+            MidiCsvRecord::NoteOn { channel, note, velocity } if velocity > 0 => {
+                open_notes.entry((channel, note)).or_default().push(line_number);
+            }
+            MidiCsvRecord::NoteOn { channel, note, .. } | MidiCsvRecord::NoteOff { channel, note, .. } => {
+                open_notes.entry((channel, note)).or_default().pop();
+            }
+            _ => (),
+        }
+    }
+    check_notes_closed(&open_notes)
+}
+
+fn check_notes_closed(open_notes: &HashMap<(u8, u8), Vec<usize>>) -> std::io::Result<()> {
+    match open_notes.values().flatten().min() {
+        Some(first_unmatched) => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Note_on_c on line {} is never turned off", first_unmatched),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Decompresses one file, where `-` stands for stdin or stdout
+pub fn decompress_file(decompressor: &mut MidiDecompressor, input_path: &Path, output_path: &Path) -> std::io::Result<()> {
+    decompressor.load_compressed_file(input_path)?;
+
+    let midi_csv = decompressor.generate_midi_csv()?;
+    validate_midi_csv(midi_csv.as_slice()).map_err(|e| Error::new(e.kind(), format!("Rejected output: {}", e)))?;
+
+    if output_path == Path::new("-") {
+        io::stdout().lock().write_all(&midi_csv)
+    } else {
+        fs::write(output_path, midi_csv)
+    }
+}
+
+/// Decompresses a single file, or every .cary file of a directory into another directory
+pub fn run(args: &DecompressArgs) -> std::io::Result<()> {
+    let mut config: DecompressorConfig = args.config.load()?;
+    if args.max_note_length.is_some() {
+        config.max_note_length = args.max_note_length;
+    }
+    let mut decompressor = MidiDecompressor::new(config, args.timing_overrides.clone());
+
+    if !args.input.is_dir() {
+        let output_path = if args.output.is_dir() {
+            let filename = match args.input.file_name() {
+                Some(name) if args.input != Path::new("-") => name.to_string_lossy(),
+                _ => "stdin".into(),
+            };
+            args.output.join(format!("reconstructed_{}", filename))
+        } else {
+            args.output.clone()
+        };
+        return decompress_file(&mut decompressor, &args.input, &output_path);
+    }
+
+    fs::create_dir_all(&args.output)?;
+    let input_dir = read_dir(&args.input)?;
+    
+    for entry in input_dir {
+        let entry = entry?;
+        let input_path = entry.path();
+        
+        // Skip non-cary files
+        if input_path.extension().is_none_or(|ext| ext != "cary") {
+            continue;
+        }
+
+        let filename = input_path.file_name().unwrap().to_string_lossy();
+        let output_path = args.output.join(format!("reconstructed_{}", filename));
+
+        eprintln!("Processing: {}", filename);
+        
+        match decompress_file(&mut decompressor, &input_path, &output_path) {
+            Ok(_) => eprintln!("Successfully reconstructed: {}", filename),
+            Err(e) => eprintln!("Error processing {}: {}", filename, e),
+        }
+    }
+
+    eprintln!("Decompression complete!");
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midicsv_compressor::{CompressorConfig, Encoding, MidiProcessor, OutputTarget};
+
+    fn decompress(cary: &str, config: DecompressorConfig) -> String {
+        let mut decompressor = MidiDecompressor::new(config, TimingOverrides::default());
+        decompressor.load_compressed(cary.as_bytes()).unwrap();
+        String::from_utf8(decompressor.generate_midi_csv().unwrap()).unwrap()
+    }
+
+    /// Tick, record type and MIDI pitch of every note event, in file order
+    fn note_events(midi_csv: &str) -> Vec<(u64, &str, i32)> {
+        midi_csv
+            .lines()
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split(", ").collect();
+                matches!(fields[2], "Note_on_c" | "Note_off_c").then(|| (fields[1].parse().unwrap(), fields[2], fields[4].parse().unwrap()))
+            })
+            .collect()
+    }
+
+    // 4/4 at 40 ticks per frame: a chord that changes under a held note, a note struck twice and a long note
+    const SONG: &str = "0, 0, Header, 1, 2, 384\n1, 0, Start_track\n1, 0, Time_signature, 4, 2, 24, 8\n1, 0, Tempo, 500000\n\
+        1, 0, End_track\n2, 0, Start_track\n2, 0, Note_on_c, 0, 60, 100\n2, 0, Note_on_c, 0, 64, 80\n\
+        2, 384, Note_off_c, 0, 60, 0\n2, 384, Note_on_c, 0, 67, 60\n2, 768, Note_off_c, 0, 64, 0\n2, 768, Note_off_c, 0, 67, 0\n\
+        2, 1536, Note_on_c, 0, 72, 100\n2, 1920, Note_off_c, 0, 72, 0\n2, 1920, Note_on_c, 0, 72, 100\n2, 2304, Note_off_c, 0, 72, 0\n\
+        2, 2320, Note_on_c, 0, 48, 100\n2, 14320, Note_off_c, 0, 48, 0\n2, 14320, End_track\n0, 0, End_of_file\n";
+
+    /// Compresses midicsv text with the compressor, as the untransposed .cary text in `encoding`
+    fn compress(midi_csv: &str, encoding: Encoding) -> String {
+        let directory = std::env::temp_dir().join(format!("decompressor_test_{}_{:?}", std::process::id(), std::thread::current().id()));
+        fs::create_dir_all(&directory).unwrap();
+        let input = directory.join("test.csv");
+        fs::write(&input, midi_csv).unwrap();
+
+        let output = directory.join("test.cary");
+        let config = CompressorConfig { encoding, ..CompressorConfig::default() };
+        let report = MidiProcessor::new(&config).process_file(&input, &OutputTarget::File(output.clone()));
+        let cary = fs::read_to_string(&output);
+        fs::remove_dir_all(&directory).unwrap();
+        report.unwrap();
+        cary.unwrap()
+    }
+
+    fn validation_error(midi_csv: &str) -> String {
+        validate_midi_csv(midi_csv.as_bytes()).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn validator_accepts_closed_notes() {
+        let midi_csv = "0, 0, Header, 1, 2, 384\n1, 0, Start_track\n1, 0, End_track\n2, 0, Start_track\n\
+            2, 0, Note_on_c, 1, 60, 100\n2, 40, Note_on_c, 1, 60, 0\n2, 40, Note_on_c, 1, 64, 90\n\
+            2, 80, Note_off_c, 1, 64, 0\n2, 80, End_track\n0, 0, End_of_file\n";
+        validate_midi_csv(midi_csv.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn validator_reports_the_first_unclosed_note() {
+        let midi_csv = "2, 0, Start_track\n2, 0, Note_on_c, 1, 60, 100\n2, 0, Note_on_c, 2, 60, 100\n\
+            2, 40, Note_off_c, 1, 60, 0\n2, 40, End_track\n";
+        assert_eq!(validation_error(midi_csv), "Note_on_c on line 3 is never turned off");
+    }
+
+    #[test]
+    fn validator_checks_tracks_without_end_track() {
+        // At the end of the file and when the next track starts
+        assert_eq!(validation_error("2, 0, Start_track\n2, 0, Note_on_c, 1, 60, 100\n"), "Note_on_c on line 2 is never turned off");
+        let midi_csv = "2, 0, Start_track\n2, 0, Note_on_c, 1, 60, 100\n3, 0, Start_track\n3, 0, End_track\n";
+        assert_eq!(validation_error(midi_csv), "Note_on_c on line 2 is never turned off");
+    }
+
+    #[test]
+    fn validator_rejects_malformed_lines() {
+        let error = validation_error("2, 0, Start_track\n2, zero, Note_on_c, 1, 60, 100\n");
+        assert!(error.starts_with("line 2: "), "{}", error);
+        validate_midi_csv("# comment\n\n2, 0, Text_t, \"a, \"\"quoted\"\" text\"\n".as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn notes_held_at_the_end_of_the_song_are_closed() {
+        // '<' and 'H' sound until the last frame, without a header a frame is 40 ticks and '!' is pitch 21
+        let midi_csv = decompress("< <H <H ", DecompressorConfig::default());
+        assert_eq!(
+            note_events(&midi_csv),
+            [(0, "Note_on_c", 48), (40, "Note_on_c", 60), (120, "Note_off_c", 48), (120, "Note_off_c", 60)]
+        );
+        validate_midi_csv(midi_csv.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn max_note_length_cuts_long_notes_short() {
+        let config = DecompressorConfig { max_note_length: Some(2), ..DecompressorConfig::default() };
+        let midi_csv = decompress("< < < < < ", config);
+        // Closed after two frames and not struck again while the pitch keeps sounding
+        assert_eq!(note_events(&midi_csv), [(0, "Note_on_c", 48), (80, "Note_off_c", 48)]);
+
+        let midi_csv = decompress("< < < < < ", DecompressorConfig::default());
+        assert_eq!(note_events(&midi_csv), [(0, "Note_on_c", 48), (200, "Note_off_c", 48)]);
+    }
+
+    #[test]
+    fn max_note_length_keeps_later_onsets() {
+        let config = DecompressorConfig { max_note_length: Some(2), ..DecompressorConfig::default() };
+        // Events encoding struck again at frame 3 while the first note was already cut short
+        let midi_csv = decompress("%cary 1\n%encoding events\n%end\non48 t3 off48 on48 t1\n", config);
+        assert_eq!(
+            note_events(&midi_csv),
+            [(0, "Note_on_c", 48), (80, "Note_off_c", 48), (120, "Note_on_c", 48), (160, "Note_off_c", 48)]
+        );
+    }
+
+    #[test]
+    fn run_length_encoding_decodes_like_plain_frames() {
+        let plain = compress(SONG, Encoding::Plain);
+        let rle = compress(SONG, Encoding::Rle);
+        assert!(rle.contains("%encoding rle\n"));
+        assert!(rle.chars().any(|c| repeat_power(c).is_some()));
+        assert!(rle.len() < plain.len() / 2);
+
+        let plain = decompress(&plain, DecompressorConfig::default());
+        let notes = note_events(&plain);
+        assert_eq!(note_events(&decompress(&rle, DecompressorConfig::default())), notes);
+        // The last note is held for 300 frames, a run of several repeat tokens
+        assert_eq!(notes.last(), Some(&(14320, "Note_off_c", 48)));
+    }
+
+    #[test]
+    fn events_decode_to_the_compressed_notes() {
+        let events = compress(SONG, Encoding::Events);
+        assert!(events.contains("%encoding events\n"));
+        let midi_csv = decompress(&events, DecompressorConfig::default());
+        // Unlike frames, events tell a note struck twice from one held on, and keep the velocity bins
+        assert_eq!(
+            note_events(&midi_csv),
+            [
+                (0, "Note_on_c", 60),
+                (0, "Note_on_c", 64),
+                (360, "Note_off_c", 60),
+                (360, "Note_on_c", 67),
+                (760, "Note_off_c", 64),
+                (760, "Note_off_c", 67),
+                (1520, "Note_on_c", 72),
+                (1920, "Note_off_c", 72),
+                (1920, "Note_on_c", 72),
+                (2280, "Note_off_c", 72),
+                (2320, "Note_on_c", 48),
+                (14320, "Note_off_c", 48),
+            ]
+        );
+        assert!(midi_csv.contains("2, 0, Note_on_c, 1, 60, 102\n"), "{}", midi_csv);
+        assert!(midi_csv.contains("2, 360, Note_on_c, 1, 67, 62\n"), "{}", midi_csv);
+        validate_midi_csv(midi_csv.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn remi_decodes_to_the_compressed_notes() {
+        let remi = compress(SONG, Encoding::Remi);
+        // Every bar starts with `bar`, positions count from it
+        let bars: Vec<&str> = remi.split("%end\n").nth(1).unwrap().lines().collect();
+        assert_eq!(bars[0], "bar meter38 pos0 tempo120 v25 pitch60 dur9 v20 pitch64 dur19 pos9 v15 pitch67 dur10");
+        // Durations stop at 128 frames, so the 300 frame note is cut short
+        assert_eq!(bars[1], "bar pos0 v25 pitch72 dur10 pos10 pitch72 dur9 pos20 pitch48 dur128");
+
+        let events = decompress(&compress(SONG, Encoding::Events), DecompressorConfig::default());
+        let mut notes = note_events(&events);
+        *notes.last_mut().unwrap() = ((38 + 20 + 128) * 40, "Note_off_c", 48);
+        assert_eq!(note_events(&decompress(&remi, DecompressorConfig::default())), notes);
+    }
+
+    #[test]
+    fn remi_positions_count_from_their_bar() {
+        let remi = "%cary 1\n%encoding remi\n%end\nbar meter4 pos0 pitch60 dur2\nbar\nbar pos1 pitch64 dur6 pos3 pitch62 dur1\n";
+        assert_eq!(
+            note_events(&decompress(remi, DecompressorConfig::default())),
+            [(0, "Note_on_c", 60), (80, "Note_off_c", 60), (360, "Note_on_c", 64), (440, "Note_on_c", 62), (480, "Note_off_c", 62), (600, "Note_off_c", 64)]
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use std::fs::{self, read_dir};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use clap::{Parser, Subcommand};
+use midi_ai_generator::GenerateArgs;
+use midi_ai_trainer::{dedup, TrainArgs, TrainerConfig};
+use midicsv_compressor::CompressArgs;
+use midicsv_decompressor::{CarySummary, DecompressArgs, DecompressorConfig, MidiDecompressor, TimingOverrides};
+use smai_config::ConfigArgs;
+
+// Exit codes, clap itself exits with 2 on bad arguments
+const EXIT_FAILURE: u8 = 1;
+const HISTOGRAM_WIDTH: usize = 40;  // Characters of the longest histogram bar
+const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+#[derive(Parser)]
+#[command(name = "smai", version, about = "Simple MIDI AI: compress, train on and generate music")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Compress midicsv files into .cary files
+    Compress(CompressArgs),
+    /// Rebuild midicsv files from .cary files
+    Decompress(DecompressArgs),
+    /// Train the network on a .cary file
+    Train(TrainArgs),
+    /// Sample .cary text from a trained network
+    Generate(GenerateArgs),
+    /// Show the header and contents of a .cary file
+    Inspect {
+        /// .cary file to inspect, `-` for stdin
+        input: PathBuf,
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    /// Summarize every .cary file of a directory: pitches, note density, polyphony and vocabulary coverage
+    Stats {
+        #[arg(default_value = midicsv_decompressor::INPUT_DIR)]
+        input: PathBuf,
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+    /// Find pieces that are near-duplicates of another in any key or tempo, as long as both were quantized on the same grid
+    Dedup {
+        #[arg(default_value = midicsv_decompressor::INPUT_DIR)]
+        input: PathBuf,
+        /// Replaces trainer.dedup_threshold from the configuration
+        #[arg(long)]
+        threshold: Option<f32>,
+        /// Delete every file of the duplicate pieces instead of only listing them. Of two near-duplicates
+        /// the piece with more files is kept, or the first by source name when they have as many
+        #[arg(long)]
+        remove: bool,
+        #[command(flatten)]
+        config: ConfigArgs,
+    },
+}
+
+fn main() -> ExitCode {
+    let result = match Cli::parse().command {
+        Command::Compress(args) => midicsv_compressor::run(&args),
+        Command::Decompress(args) => midicsv_decompressor::run(&args),
+        Command::Train(args) => midi_ai_trainer::run(&args),
+        Command::Generate(args) => midi_ai_generator::run(&args),
+        Command::Inspect { input, config } => config.load().and_then(|config| inspect(&input, config)),
+        Command::Stats { input, config } => config.load().and_then(|config| stats(&input, config)),
+        Command::Dedup { input, threshold, remove, config } => config
+            .load()
+            .and_then(|config: TrainerConfig| dedup_corpus(&input, threshold.unwrap_or(config.dedup_threshold), remove)),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::from(EXIT_FAILURE)
+        }
+    }
+}
+
+fn inspect(path: &Path, config: DecompressorConfig) -> io::Result<()> {
+    let mut decompressor = MidiDecompressor::new(config, TimingOverrides::default());
+    decompressor.load_compressed_file(path)?;
+    for (key, value) in decompressor.header_entries() {
+        println!("{}: {}", key, value);
+    }
+    print_summary(&decompressor.summary());
+    Ok(())
+}
+
+fn stats(dir: &Path, config: DecompressorConfig) -> io::Result<()> {
+    let mut decompressor = MidiDecompressor::new(config, TimingOverrides::default());
+    let mut files = 0;
+    let mut failures = Vec::new();
+    let mut total = CarySummary::default();
+
+    // A file that can't be read is reported and left out of the totals, the way batch compression does
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|extension| extension != "cary") {
+            continue;
+        }
+        match decompressor.load_compressed_file(&path) {
+            Ok(()) => {
+                total.merge(&decompressor.summary());
+                files += 1;
+            }
+            Err(e) => failures.push(format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    println!("files: {}", files);
+    print_summary(&total);
+    println!(
+        "note density: {:.3} notes per frame, {:.3} per sounding frame",
+        total.onsets as f64 / total.frames.max(1) as f64,
+        total.onsets as f64 / total.sounding_frames.max(1) as f64
+    );
+    println!(
+        "vocabulary coverage: {} of {} pitch characters, {} distinct chords",
+        total.characters.len(),
+        CarySummary::vocabulary_size(),
+        total.chords.len()
+    );
+
+    println!("pitch histogram (onsets):");
+    let pitches = total.pitch_onsets.iter().enumerate().filter(|(_, count)| **count > 0);
+    print_histogram(pitches.map(|(pitch, count)| (format!("{:>3} {:<3}", pitch, note_name(pitch)), *count)));
+    println!("polyphony distribution (frames):");
+    print_histogram(total.polyphony_frames.iter().enumerate().map(|(notes, count)| (format!("{:>3} notes", notes), *count)));
+
+    if failures.is_empty() {
+        return Ok(());
+    }
+    eprintln!("Left out {} files that could not be read", failures.len());
+    for failure in &failures {
+        eprintln!("  {}", failure);
+    }
+    Err(io::Error::other(format!("{} of {} files failed", failures.len(), files + failures.len())))
+}
+
+fn dedup_corpus(dir: &Path, threshold: f32, remove: bool) -> io::Result<()> {
+    let pieces = dedup::load_pieces(dir)?;
+    let duplicates = dedup::find_duplicates(&pieces, threshold);
+
+    for duplicate in &duplicates {
+        let piece = &pieces[duplicate.piece];
+        println!(
+            "{} ({} files) is {:.0}% similar to {}",
+            piece.source,
+            piece.files.len(),
+            100.0 * duplicate.similarity,
+            pieces[duplicate.original].source
+        );
+        if remove {
+            for file in &piece.files {
+                fs::remove_file(file)?;
+            }
+        }
+    }
+
+    let files: usize = duplicates.iter().map(|duplicate| pieces[duplicate.piece].files.len()).sum();
+    let action = if remove { "removed" } else { "found" };
+    println!("{} {} duplicate pieces ({} files) among {}", action, duplicates.len(), files, pieces.len());
+    Ok(())
+}
+
+fn note_name(pitch: usize) -> String {
+    // MIDI note 60 is C4
+    format!("{}{}", NOTE_NAMES[pitch % 12], pitch as i32 / 12 - 1)
+}
+
+/// One line per bucket: label, count, share of the total and a bar scaled to the largest bucket
+fn print_histogram(buckets: impl Iterator<Item = (String, usize)>) {
+    let buckets: Vec<_> = buckets.collect();
+    let total: usize = buckets.iter().map(|(_, count)| count).sum();
+    let largest = buckets.iter().map(|(_, count)| *count).max().unwrap_or(0).max(1);
+    for (label, count) in buckets {
+        println!(
+            "  {}  {:>9}  {:>5.1}%  {}",
+            label,
+            count,
+            100.0 * count as f64 / total as f64,
+            "#".repeat(count * HISTOGRAM_WIDTH / largest)
+        );
+    }
+}
+
+fn print_summary(summary: &CarySummary) {
+    println!("frames: {}", summary.frames);
+    println!("sounding frames: {}", summary.sounding_frames);
+    println!("notes: {}", summary.onsets);
+    match (summary.lowest_pitch, summary.highest_pitch) {
+        (Some(lowest), Some(highest)) => println!("pitch range: {}..={}", lowest, highest),
+        _ => println!("pitch range: none"),
+    }
+    println!("max polyphony: {}", summary.max_polyphony);
+}
@@ -0,0 +1,104 @@
+//! Symbols of the .cary format, shared by everything that writes or reads it
+
+pub const CARY_FORMAT_VERSION: u32 = 1;
+pub const MIDI_PITCHES: usize = 128;  // Note numbers of the events and REMI encodings
+pub const BAR_MARKER: char = '\n';
+pub const BEAT_MARKER: char = '\t';
+pub const REPEAT_TOKEN: char = '\u{c0}';  // 'À' repeats the previous frame once, each following character doubles the count
+pub const REPEAT_TOKENS: u32 = 14;  // Up to 'Í', 8192 repeats
+
+/// Power of two a repeat token stands for, None for every other character
+pub fn repeat_power(c: char) -> Option<u32> {
+    let power = (c as u32).checked_sub(REPEAT_TOKEN as u32)?;
+    (power < REPEAT_TOKENS).then_some(power)
+}
+
+/// Repeat tokens for a run of `repeats` copies of the previous frame, largest first
+pub fn repeat_tokens(mut repeats: usize) -> String {
+    let mut tokens = String::new();
+    while repeats > 0 {
+        let power = repeats.ilog2().min(REPEAT_TOKENS - 1);
+        tokens.push(char::from_u32(REPEAT_TOKEN as u32 + power).unwrap_or(REPEAT_TOKEN));
+        repeats -= 1 << power;
+    }
+    tokens
+}
+
+// Words of the events encoding: `on<pitch>`, `off<pitch>`, `t<frames>`, `v<bin>` and the meter markers
+pub const MAX_TIME_SHIFT: usize = 64;  // Frames per time shift, longer gaps take several
+pub const VELOCITY_BINS: u8 = 32;
+pub const EVENT_MARKERS: [&str; 2] = ["bar", "beat"];
+const VELOCITY_BIN_SIZE: u32 = 128 / VELOCITY_BINS as u32;
+
+/// Every word the events encoding can write, in the order vocabularies number them
+pub fn event_words() -> Vec<String> {
+    let mut words = Vec::new();
+    words.extend((0..MIDI_PITCHES).map(|pitch| format!("on{}", pitch)));
+    words.extend((0..MIDI_PITCHES).map(|pitch| format!("off{}", pitch)));
+    words.extend((1..=MAX_TIME_SHIFT).map(|frames| format!("t{}", frames)));
+    words.extend((0..VELOCITY_BINS).map(|bin| format!("v{}", bin)));
+    words.extend(EVENT_MARKERS.map(str::to_string));
+    words
+}
+
+// Words of the REMI encoding: `bar`, `meter<frames>`, `pos<frame>`, `tempo<bpm>`, `v<bin>`, `pitch<p>` and `dur<frames>`
+pub const MAX_BAR_FRAMES: usize = 192;  // Longer bars are split, so positions stay below this
+pub const MAX_DURATION: usize = 128;  // Frames, longer notes are cut short
+pub const TEMPO_STEP: u32 = 4;  // Beats per minute between tempo tokens
+pub const TEMPO_RANGE: (u32, u32) = (20, 300);
+
+/// Every word the REMI encoding can write, in the order vocabularies number them
+pub fn remi_words() -> Vec<String> {
+    let mut words = vec!["bar".to_string()];
+    words.extend((1..=MAX_BAR_FRAMES).map(|length| format!("meter{}", length)));
+    words.extend((0..MAX_BAR_FRAMES).map(|position| format!("pos{}", position)));
+    words.extend((TEMPO_RANGE.0..=TEMPO_RANGE.1).step_by(TEMPO_STEP as usize).map(|bpm| format!("tempo{}", bpm)));
+    words.extend((0..VELOCITY_BINS).map(|bin| format!("v{}", bin)));
+    words.extend((0..MIDI_PITCHES).map(|pitch| format!("pitch{}", pitch)));
+    words.extend((1..=MAX_DURATION).map(|duration| format!("dur{}", duration)));
+    words
+}
+
+/// Bin of the `v<bin>` token for a velocity
+pub fn bin_of_velocity(velocity: u8) -> u8 {
+    (velocity as u32 / VELOCITY_BIN_SIZE) as u8
+}
+
+/// Velocity a bin decodes to, the middle of its range
+pub fn velocity_of_bin(bin: u32) -> u8 {
+    (bin * VELOCITY_BIN_SIZE + VELOCITY_BIN_SIZE / 2).min(127) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repeats(tokens: &str) -> usize {
+        tokens.chars().map(|c| 1 << repeat_power(c).unwrap()).sum()
+    }
+
+    #[test]
+    fn repeat_tokens_add_up_to_the_run() {
+        for run in [1, 2, 3, 4, 7, 8, 9, 255, 256, 257, 8191, 8192, 8193, 16383, 16384, 20000] {
+            assert_eq!(repeats(&repeat_tokens(run)), run, "{}", run);
+        }
+        assert_eq!(repeat_tokens(0), "");
+        assert_eq!(repeat_tokens(1), "À");
+        assert_eq!(repeat_tokens(2), "Á");
+        assert_eq!(repeat_tokens(3), "ÁÀ");
+        assert_eq!(repeat_tokens(8191), "ÌËÊÉÈÇÆÅÄÃÂÁÀ");
+        // Longer runs repeat the largest token
+        assert_eq!(repeat_tokens(8192), "Í");
+        assert_eq!(repeat_tokens(16385), "ÍÍÀ");
+    }
+
+    #[test]
+    fn only_repeat_tokens_have_a_power() {
+        assert_eq!(repeat_power('À'), Some(0));
+        assert_eq!(repeat_power('Í'), Some(REPEAT_TOKENS - 1));
+        assert_eq!(repeat_power('Î'), None);
+        assert_eq!(repeat_power('¿'), None);
+        assert_eq!(repeat_power(' '), None);
+        assert_eq!(repeat_power('~'), None);
+    }
+}
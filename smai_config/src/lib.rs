@@ -0,0 +1,100 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use clap::Args;
+use serde::de::DeserializeOwned;
+use toml::{Table, Value};
+
+pub mod format;
+pub mod hash;
+
+// Constants
+pub const CONFIG_FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../smai.toml");
+const SECTIONS: [&str; 4] = ["compressor", "decompressor", "trainer", "generator"];
+
+/// Where the configuration comes from, shared by every binary
+#[derive(Args, Clone, Default)]
+pub struct ConfigArgs {
+    /// TOML configuration file, smai.toml at the repository root when it exists
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Replace one configuration value, e.g. `--set trainer.epochs=20` or `--set compressor.errors.out_of_range=fail`
+    #[arg(long = "set", value_name = "SECTION.KEY=VALUE")]
+    pub overrides: Vec<String>,
+}
+
+/// One `[section]` of the configuration file. Missing keys take their defaults,
+/// unknown keys are rejected so typos don't silently fall back to them.
+pub trait Section: DeserializeOwned {
+    const NAME: &'static str;
+
+    /// Checks the values make sense together, describing the first problem found
+    fn validate(&self) -> Result<(), String>;
+}
+
+impl ConfigArgs {
+    pub fn load<T: Section>(&self) -> io::Result<T> {
+        let mut table = self.read_table()?;
+        let section = table.remove(T::NAME).unwrap_or_else(|| Value::Table(Table::new()));
+
+        let config = T::deserialize(section).map_err(|e| invalid(format!("[{}] {}", T::NAME, e)))?;
+        config.validate().map_err(|e| invalid(format!("[{}] {}", T::NAME, e)))?;
+        Ok(config)
+    }
+
+    fn read_table(&self) -> io::Result<Table> {
+        let path = match &self.config {
+            Some(path) => Some(path.as_path()),
+            None => Some(Path::new(CONFIG_FILE)).filter(|path| path.exists()),
+        };
+        let mut table = match path {
+            Some(path) => fs::read_to_string(path)?
+                .parse::<Table>()
+                .map_err(|e| invalid(format!("{}: {}", path.display(), e)))?,
+            None => Table::new(),
+        };
+
+        if let Some(section) = table.keys().find(|section| !SECTIONS.contains(&section.as_str())) {
+            return Err(invalid(format!("unknown section [{}]", section)));
+        }
+        for assignment in &self.overrides {
+            apply_override(&mut table, assignment)?;
+        }
+        Ok(table)
+    }
+}
+
+fn apply_override(table: &mut Table, assignment: &str) -> io::Result<()> {
+    let usage = || invalid(format!("expected SECTION.KEY=VALUE, got {:?}", assignment));
+    let (path, value) = assignment.split_once('=').ok_or_else(usage)?;
+    let (tables, key) = path.trim().rsplit_once('.').ok_or_else(usage)?;
+    let section = tables.split('.').next().unwrap_or(tables);
+    if !SECTIONS.contains(&section) {
+        return Err(invalid(format!("unknown section [{}]", section)));
+    }
+
+    // Nested keys such as compressor.errors.out_of_range walk down one table per dot
+    let mut table = table;
+    for name in tables.split('.') {
+        table = table
+            .entry(name)
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| invalid(format!("{} is not a table", name)))?;
+    }
+    table.insert(key.to_string(), parse_value(value.trim()));
+    Ok(())
+}
+
+/// Reads a value written as in the file, anything that isn't valid TOML counts as a bare string
+fn parse_value(text: &str) -> Value {
+    format!("value = {}", text)
+        .parse::<Table>()
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(text.to_string()))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}